@@ -0,0 +1,312 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+//Token bucket holding up to one second of traffic, a rate of 0 means unlimited
+pub struct RateLimiter {
+    rate: AtomicU64,
+    //Tokens left and when they were last refilled, they go negative while callers wait for their reservation
+    bucket: Mutex<(f64, Instant)>,
+}
+
+//Upload and download limits of one level, in bytes per second
+pub struct Bandwidth {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+pub struct SessionBandwidth {
+    pub limits: Bandwidth,
+    //Peers on the local network are not limited at all
+    pub exempt_local: AtomicBool,
+}
+
+pub struct TorrentBandwidth {
+    pub session: Arc<SessionBandwidth>,
+    pub limits: Bandwidth,
+
+    //Limits given to each peer of the torrent
+    peer_upload: AtomicU64,
+    peer_download: AtomicU64,
+    peers: Mutex<Vec<Weak<Bandwidth>>>,
+}
+
+//Limiters a connection goes through, from the whole session down to the peer itself
+pub struct PeerBandwidth {
+    pub torrent: Arc<TorrentBandwidth>,
+    pub limits: Arc<Bandwidth>,
+    local: bool,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+
+        if let Ok(mut bucket) = self.bucket.lock() {
+            bucket.0 = bucket.0.min(rate as f64);
+        }
+    }
+
+    //Takes the tokens right away and returns how long to wait for them, reservations are served in order so peers share the rate evenly
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        let rate = self.rate();
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let mut bucket = match self.bucket.lock() {
+            Ok(bucket) => bucket,
+            Err(_) => return Duration::ZERO,
+        };
+
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.1).as_secs_f64() * rate as f64;
+        bucket.0 = (bucket.0 + refill).min(rate as f64) - bytes as f64;
+        bucket.1 = now;
+
+        match bucket.0 < 0.0 {
+            true => Duration::from_secs_f64(-bucket.0 / rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+impl Bandwidth {
+    pub fn new(upload: u64, download: u64) -> Self {
+        Self {
+            upload: RateLimiter::new(upload),
+            download: RateLimiter::new(download),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Bandwidth::new(0, 0)
+    }
+
+    pub fn set_rates(&self, upload: u64, download: u64) {
+        self.upload.set_rate(upload);
+        self.download.set_rate(download);
+    }
+}
+
+impl SessionBandwidth {
+    pub fn new(limits: Bandwidth, exempt_local: bool) -> Self {
+        Self {
+            limits,
+            exempt_local: AtomicBool::new(exempt_local),
+        }
+    }
+}
+
+impl Default for SessionBandwidth {
+    fn default() -> Self {
+        SessionBandwidth::new(Bandwidth::unlimited(), false)
+    }
+}
+
+impl TorrentBandwidth {
+    pub fn new(session: Arc<SessionBandwidth>) -> Self {
+        Self {
+            session,
+            limits: Bandwidth::unlimited(),
+
+            peer_upload: AtomicU64::new(0),
+            peer_download: AtomicU64::new(0),
+            peers: Mutex::new(Vec::new()),
+        }
+    }
+
+    //Applies to the peers already connected too
+    pub fn set_peer_rates(&self, upload: u64, download: u64) {
+        self.peer_upload.store(upload, Ordering::Relaxed);
+        self.peer_download.store(download, Ordering::Relaxed);
+
+        if let Ok(mut peers) = self.peers.lock() {
+            peers.retain(|peer| peer.strong_count() > 0);
+            peers.iter().filter_map(|peer| peer.upgrade()).for_each(|peer| peer.set_rates(upload, download));
+        }
+    }
+
+    pub fn peer(self: &Arc<Self>, ip: IpAddr) -> PeerBandwidth {
+        let limits = Arc::new(Bandwidth::new(self.peer_upload.load(Ordering::Relaxed), self.peer_download.load(Ordering::Relaxed)));
+
+        if let Ok(mut peers) = self.peers.lock() {
+            peers.retain(|peer| peer.strong_count() > 0);
+            peers.push(Arc::downgrade(&limits));
+        }
+
+        PeerBandwidth {
+            torrent: self.clone(),
+            limits,
+            local: is_local(ip),
+        }
+    }
+}
+
+impl PeerBandwidth {
+    pub async fn upload(&self, bytes: usize) {
+        if self.is_exempt() {
+            return;
+        }
+
+        let wait = self.torrent.session.limits.upload.reserve(bytes)
+            .max(self.torrent.limits.upload.reserve(bytes))
+            .max(self.limits.upload.reserve(bytes));
+
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+
+    pub async fn download(&self, bytes: usize) {
+        if self.is_exempt() {
+            return;
+        }
+
+        let wait = self.torrent.session.limits.download.reserve(bytes)
+            .max(self.torrent.limits.download.reserve(bytes))
+            .max(self.limits.download.reserve(bytes));
+
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+
+    fn is_exempt(&self) -> bool {
+        self.local && self.torrent.session.exempt_local.load(Ordering::Relaxed)
+    }
+}
+
+//Loopback, private and link local addresses
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_never_waits() {
+        let limiter = RateLimiter::new(0);
+
+        for _ in 0..10 {
+            assert_eq!(limiter.reserve(1 << 20), Duration::ZERO);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reservations_wait_for_their_tokens() {
+        let limiter = RateLimiter::new(1000);
+
+        //The bucket starts full
+        assert_eq!(limiter.reserve(1000), Duration::ZERO);
+        assert_eq!(limiter.reserve(500), Duration::from_millis(500));
+        //Reservations queue up behind the previous ones
+        assert_eq!(limiter.reserve(500), Duration::from_secs(1));
+
+        time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.reserve(0), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_is_capped_to_one_second() {
+        let limiter = RateLimiter::new(1000);
+        limiter.reserve(1000);
+
+        //Idling for long does not let a burst through
+        time::advance(Duration::from_secs(60)).await;
+        assert_eq!(limiter.reserve(1000), Duration::ZERO);
+        assert_eq!(limiter.reserve(250), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lowering_the_rate_shrinks_the_bucket() {
+        let limiter = RateLimiter::new(1000);
+
+        limiter.set_rate(100);
+        assert_eq!(limiter.rate(), 100);
+        assert_eq!(limiter.reserve(100), Duration::ZERO);
+        assert_eq!(limiter.reserve(50), Duration::from_millis(500));
+
+        //Removing the limit lets everything through at once
+        limiter.set_rate(0);
+        assert_eq!(limiter.reserve(1 << 20), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_level_limits_the_peer() {
+        let session = Arc::new(SessionBandwidth::new(Bandwidth::new(1000, 0), false));
+        let torrent = Arc::new(TorrentBandwidth::new(session.clone()));
+        torrent.limits.set_rates(0, 1000);
+        let peer = torrent.peer(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+
+        //The strictest level decides, here the session for uploads and the torrent for downloads
+        let start = Instant::now();
+        peer.upload(2000).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        peer.download(3000).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        //Peer limits apply to the peers already connected
+        torrent.set_peer_rates(0, 100);
+        assert_eq!(peer.limits.download.rate(), 100);
+        assert_eq!(torrent.peer(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 5))).limits.download.rate(), 100);
+        let start = Instant::now();
+        peer.download(200).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn local_peers_can_be_exempt() {
+        let session = Arc::new(SessionBandwidth::new(Bandwidth::new(100, 100), true));
+        let torrent = Arc::new(TorrentBandwidth::new(session.clone()));
+        let local = torrent.peer(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)));
+        let remote = torrent.peer(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+
+        let start = Instant::now();
+        local.upload(10000).await;
+        local.download(10000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        remote.upload(200).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        //Limited again once the exemption is lifted
+        session.exempt_local.store(false, Ordering::Relaxed);
+        let start = Instant::now();
+        local.download(200).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn local_addresses() {
+        assert!(is_local("127.0.0.1".parse().unwrap()));
+        assert!(is_local("10.1.2.3".parse().unwrap()));
+        assert!(is_local("169.254.0.1".parse().unwrap()));
+        assert!(is_local("::1".parse().unwrap()));
+        assert!(is_local("fd00::1".parse().unwrap()));
+        assert!(is_local("fe80::1".parse().unwrap()));
+        assert!(!is_local("8.8.8.8".parse().unwrap()));
+        assert!(!is_local("2001:db8::1".parse().unwrap()));
+    }
+}
@@ -1,62 +1,154 @@
+use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use async_channel::Receiver;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use crate::shared::SyncResult;
+use tokio::time;
+use crate::connection::bandwidth::PeerBandwidth;
+use crate::engine::choker::{ChokerCommand, PeerSlot};
+use crate::engine::context::PeerContext;
+use crate::error::Error;
+use crate::shared::{SizedBytes, SyncResult};
 use crate::types::bitfield::BitField;
+use crate::types::extension::ExtendedHandshake;
 use crate::types::message::{Handshake, Message, MessageCode};
 use crate::types::peer::Peer;
 
 pub struct Client {
     pub connection: TcpStream,
+    //Whether the peer is choking us
     pub choked: bool,
+    //Whether we are choking the peer
+    pub am_choking: bool,
+    //Whether we told the peer we are interested
+    pub am_interested: bool,
+    pub peer_interested: bool,
 
     pub bitfield: BitField,
     pub peer: Peer,
     pub info_hash: String,
+    pub peer_id: SizedBytes,
+
+    //Capability flags from the peer handshake
+    pub reserved: [u8; 8],
+    //Extended handshake of the peer, once received
+    pub extended: Option<ExtendedHandshake>,
+
+    //Pieces the peer lets us request while it chokes us
+    pub allowed_fast: HashSet<u32>,
+    //Pieces we let the peer request while we choke it
+    pub granted_fast: HashSet<u32>,
+    //Pieces the peer suggested we download, most recent last
+    pub suggested: Vec<u32>,
+
+    //Number of pieces received from this peer that did not match their hash
+    pub hash_failures: u32,
+
+    //Our entry in the choker, which tells us when to choke or unchoke the peer
+    pub slot: Arc<PeerSlot>,
+    pub choker_commands: Receiver<ChokerCommand>,
+    pub bandwidth: PeerBandwidth,
 }
 
 impl Client {
-    pub async fn connect(peer: Peer, info_hash: String) -> SyncResult<Client> {
+    pub async fn connect(peer: Peer, context: &PeerContext) -> SyncResult<Client> {
+        let settings = context.settings.get();
         let address = SocketAddr::new(peer.ip, peer.port);
-        println!("[Client - connect] Socket address built");
-        let mut connection = TcpStream::connect(address).await?;
-        println!("[Client - connect] Connection established");
+        let connection = time::timeout(settings.connect_timeout(), TcpStream::connect(address)).await
+            .map_err(|_| Error::PeerTimeout("Peer connection"))?.map_err(Error::Network)?;
 
-        let _handshake = Client::complete_handshake(&mut connection, info_hash.clone()).await?;
-        println!("[Client - connect] Handshake completed");
-        let bitfield = Client::receive_bitfield(&mut connection).await?;
-        println!("[Client - connect] Bitfield received");
+        //A peer accepting the connection then going silent would keep its half-open slot forever
+        time::timeout(settings.handshake_timeout(), Client::open(connection, peer, context)).await
+            .map_err(|_| Error::PeerTimeout("Peer handshake"))?
+    }
+
+    //Exchanges the handshakes and the first messages on a connection we dialed
+    async fn open(mut connection: TcpStream, peer: Peer, context: &PeerContext) -> SyncResult<Client> {
+        let handshake = Client::complete_handshake(&mut connection, context.info_hash.clone(), context.peer_id).await?;
+        //Trackers may hand us our own address
+        if handshake.peer_id == context.peer_id {
+            return Err(Error::Refused("Connected to ourselves"));
+        }
+
+        let mut client = Client::from_connection(connection, peer, &handshake, context);
+        client.send_bitfield(context).await?;
+        client.send_extended_handshake(context).await?;
+        client.send_allowed_fast(context).await?;
+
+        client.receive_bitfield(context).await?;
+
+        Ok(client)
+    }
+
+    //Wraps a connection whose handshake is already done, the peer bitfield is empty until it sends one
+    pub fn from_connection(connection: TcpStream, peer: Peer, handshake: &Handshake, context: &PeerContext) -> Client {
+        let (slot, choker_commands) = context.choker.register(peer.clone());
+        let peer_ip = peer.ip;
 
-        let client = Client {
+        Client {
             connection,
-            choked: false,
+            choked: true,
+            am_choking: true,
+            am_interested: false,
+            peer_interested: false,
 
-            bitfield,
+            bitfield: BitField::empty(context.piece_count),
             peer,
-            info_hash,
-        };
+            info_hash: context.info_hash.clone(),
+            peer_id: handshake.peer_id,
 
-        Ok(client)
+            reserved: handshake.reserved,
+            extended: None,
+
+            allowed_fast: HashSet::new(),
+            granted_fast: HashSet::new(),
+            suggested: Vec::new(),
+
+            hash_failures: 0,
+
+            slot,
+            choker_commands,
+            bandwidth: context.bandwidth.peer(peer_ip),
+        }
     }
 
-    pub async fn complete_handshake(connection: &mut TcpStream, info_hash: String) -> SyncResult<Handshake> {
-        let handshake = Handshake::new(info_hash.clone())?;
-        println!("[Client - complete_handshake] Handshake built");
+    //Every change to the peer pieces goes through here so the picker availability stays right
+    pub fn set_bitfield(&mut self, bitfield: BitField, context: &PeerContext) -> SyncResult<()> {
+        context.picker.remove_peer(&self.bitfield)?;
+        context.picker.add_peer(&bitfield)?;
+        self.bitfield = bitfield;
+
+        Ok(())
+    }
+
+    pub fn receive_have(&mut self, index: u32, context: &PeerContext) -> SyncResult<()> {
+        if index >= context.piece_count {
+            return Err(Error::Protocol("Have message for a piece out of bounds".into()));
+        }
+
+        if !self.bitfield.has_piece(index) {
+            self.bitfield.set_piece(index);
+            context.picker.add_have(index)?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn complete_handshake(connection: &mut TcpStream, info_hash: String, peer_id: SizedBytes) -> SyncResult<Handshake> {
+        let handshake = Handshake::new(info_hash.clone(), peer_id);
         let bytes = handshake.to_bytes()?;
-        println!("[Client - complete_handshake] Handshake bytes built");
 
-        connection.write_all(&bytes).await?;
-        println!("[Client - complete_handshake] Handshake bytes written");
+        connection.write_all(&bytes).await.map_err(Error::Network)?;
 
         let handshake = Client::read_handshake(connection).await?;
-        println!("[Client - complete_handshake] Handshake read");
 
         if handshake.pstr != "BitTorrent protocol" {
-            return Err("Invalid pstr in handshake".into());
+            return Err(Error::Protocol("Invalid pstr in handshake".into()));
         }
 
         if handshake.info_hash != info_hash {
-            return Err("Invalid info_hash in handshake".into());
+            return Err(Error::Protocol("Invalid info_hash in handshake".into()));
         }
 
         Ok(handshake)
@@ -64,40 +156,51 @@ impl Client {
 
     pub async fn read_handshake(connection: &mut TcpStream) -> SyncResult<Handshake> {
         let mut buffer = [0; 1];
-        println!("[Client - read_handshake] Buffer initialized");
-        connection.read_exact(&mut buffer).await?;
-        println!("[Client - read_handshake] pstrlen read");
+        connection.read_exact(&mut buffer).await.map_err(Error::Network)?;
 
         let pstr_len = buffer[0] as usize;
-        println!("[Client - read_handshake] pstrlen is: {}", pstr_len);
 
         let mut buffer = vec![0; pstr_len + 20 + 20 + 8];
-        connection.read_exact(&mut buffer).await?;
-        println!("[Client - read_handshake] Read handshake");
+        connection.read_exact(&mut buffer).await.map_err(Error::Network)?;
 
         let handshake = Handshake::from_bytes(pstr_len, &buffer)?;
-        println!("[Client - read_handshake] Handshake built from bytes");
 
         Ok(handshake)
     }
 
-    pub async fn receive_bitfield(connection: &mut TcpStream) -> SyncResult<BitField> {
-        let message = Client::static_read_message(connection).await?;
-        println!("[Client - receive_bitfield] Message read");
+    //Peers without the fast extension skip the bitfield when they have nothing, their first message is then handled like any other
+    pub async fn receive_bitfield(&mut self, context: &PeerContext) -> SyncResult<()> {
+        let mut message = self.read_message().await?;
+
+        //The extended handshake may come before the bitfield
+        while message.id == MessageCode::MessageExtended {
+            self.receive_extended(&message, context).await?;
+            message = self.read_message().await?;
+        }
 
-        println!("[Client - receive_bitfield] Received message id: {}", <MessageCode as Into<u8>>::into(message.id.clone()));
-        if message.id != MessageCode::MessageBitfield {
-            return Err("Received non-bitfield message when expecting bitfield".into());
+        match message.id {
+            MessageCode::MessageBitfield => {
+                let bitfield = Client::checked_bitfield(&message.payload, context)?;
+                self.set_bitfield(bitfield, context)
+            },
+            MessageCode::MessageHaveAll | MessageCode::MessageHaveNone if self.supports_fast() => self.receive_fast(&message, context),
+            _ if self.supports_fast() => Err(Error::Protocol("Fast extension peer did not start with its pieces".into())),
+            _ => self.handle_message(&message, context).await,
         }
+    }
 
-        let bitfield = BitField::new(message.payload);
+    //A bitfield of another size than the piece count is a protocol violation, whatever it holds
+    pub fn checked_bitfield(payload: &[u8], context: &PeerContext) -> SyncResult<BitField> {
+        if payload.len() != BitField::empty(context.piece_count).bits.len() {
+            return Err(Error::Protocol("Bitfield has an invalid length".into()));
+        }
 
-        Ok(bitfield)
+        Ok(BitField::new(payload.to_vec()))
     }
 
     pub async fn static_read_message(connection: &mut TcpStream) -> SyncResult<Message> {
         let mut buffer = [0; 4];
-        connection.read_exact(&mut buffer).await?;
+        connection.read_exact(&mut buffer).await.map_err(Error::Network)?;
 
         let length = u32::from_be_bytes(buffer);
 
@@ -107,39 +210,65 @@ impl Client {
         }
 
         let mut buffer = vec![0; length as usize];
-        connection.read_exact(&mut buffer).await?;
+        connection.read_exact(&mut buffer).await.map_err(Error::Network)?;
 
         let message = Message::from_bytes(&buffer)?;
 
         Ok(message)
     }
 
+    //The message is read first, waiting afterwards holds back the next one so TCP slows the peer down
     pub async fn read_message(&mut self) -> SyncResult<Message> {
-        Client::static_read_message(&mut self.connection).await
+        let message = Client::static_read_message(&mut self.connection).await?;
+        self.bandwidth.download(message.payload.len() + 5).await;
+
+        Ok(message)
     }
 
     pub async fn send_message(&mut self, message: Message) -> SyncResult<()> {
         let bytes = message.to_bytes()?;
+        self.bandwidth.upload(bytes.len()).await;
 
-        self.connection.write_all(&bytes).await?;
+        self.connection.write_all(&bytes).await.map_err(Error::Network)?;
 
         Ok(())
     }
 
     pub async fn send_interested(&mut self) -> SyncResult<()> {
         let message = Message::new(MessageCode::MessageInterested, Vec::new());
+        self.am_interested = true;
 
         self.send_message(message).await
     }
 
     pub async fn send_not_interested(&mut self) -> SyncResult<()> {
         let message = Message::new(MessageCode::MessageNotInterested, Vec::new());
+        self.am_interested = false;
+
+        self.send_message(message).await
+    }
+
+    //Tells the peer whether it has a piece we need, only when that changed since we last told it
+    pub async fn update_interest(&mut self, context: &PeerContext) -> SyncResult<()> {
+        let interested = context.picker.wants_any(&self.bitfield)?;
+
+        match (interested, self.am_interested) {
+            (true, false) => self.send_interested().await,
+            (false, true) => self.send_not_interested().await,
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn send_choke(&mut self) -> SyncResult<()> {
+        let message = Message::new(MessageCode::MessageChoke, Vec::new());
+        self.am_choking = true;
 
         self.send_message(message).await
     }
 
     pub async fn send_unchoke(&mut self) -> SyncResult<()> {
         let message = Message::new(MessageCode::MessageUnchoke, Vec::new());
+        self.am_choking = false;
 
         self.send_message(message).await
     }
@@ -150,9 +279,35 @@ impl Client {
         self.send_message(message).await
     }
 
+    pub async fn send_cancel(&mut self, index: u32, begin: u32, length: u32) -> SyncResult<()> {
+        let message = Message::format_cancel(index, begin, length);
+
+        self.send_message(message).await
+    }
+
     pub async fn send_have(&mut self, index: u32) -> SyncResult<()> {
         let message = Message::format_have(index);
 
         self.send_message(message).await
     }
+
+    pub async fn send_piece(&mut self, index: u32, begin: u32, block: &[u8]) -> SyncResult<()> {
+        let message = Message::format_piece(index, begin, block);
+
+        self.send_message(message).await
+    }
+
+    //Peers without any piece yet skip the bitfield, which the protocol allows, unless the fast extension asks for have none
+    pub async fn send_bitfield(&mut self, context: &PeerContext) -> SyncResult<()> {
+        let have = context.have.read().map_err(|_| Error::Poisoned("Have bitfield"))?.clone();
+
+        let message = match (self.supports_fast(), have.count_pieces()) {
+            (true, 0) => Message::new(MessageCode::MessageHaveNone, Vec::new()),
+            (true, _) if have.is_complete(context.piece_count) => Message::new(MessageCode::MessageHaveAll, Vec::new()),
+            (false, 0) => return Ok(()),
+            _ => Message::format_bitfield(&have),
+        };
+
+        self.send_message(message).await
+    }
 }
\ No newline at end of file
@@ -0,0 +1,146 @@
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use crate::connection::client::Client;
+use crate::engine::context::PeerContext;
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::types::extension::{EXTENDED_HANDSHAKE_ID, ExtendedHandshake};
+use crate::types::message::{EXTENSION_PROTOCOL_FLAG, Message};
+use crate::types::peer::Peer;
+
+//A message type carried over the extension protocol (BEP 10), called for every peer connection supporting it
+pub trait Extension: Send + Sync {
+    //Key of the extension in the m dictionary, like ut_metadata
+    fn name(&self) -> &str;
+
+    //Adds extension specific keys to the extended handshake we send
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    //The peer listed this extension in its extended handshake
+    fn on_handshake(&self, _peer: &Peer, _handshake: &ExtendedHandshake) -> SyncResult<()> {
+        Ok(())
+    }
+
+    //Returns the payload to answer with, if any, it is sent with the id the peer chose for this extension
+    fn on_message(&self, peer: &Peer, payload: &[u8]) -> SyncResult<Option<Vec<u8>>>;
+
+    fn on_disconnect(&self, _peer: &Peer) {}
+}
+
+//Extensions of a torrent, the local id of each one is its position plus one since 0 is the handshake
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    extensions: RwLock<Vec<Arc<dyn Extension>>>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //Only connections opened after the registration advertise the extension
+    pub fn register(&self, extension: Arc<dyn Extension>) -> SyncResult<u8> {
+        let mut extensions = self.extensions.write().map_err(|_| Error::Poisoned("Extension registry"))?;
+
+        if extensions.iter().any(|registered| registered.name() == extension.name()) {
+            return Err(Error::Extension(format!("Extension {} is already registered", extension.name())));
+        }
+
+        if extensions.len() >= u8::MAX as usize {
+            return Err(Error::Extension("Too many extensions registered".into()));
+        }
+
+        extensions.push(extension);
+
+        Ok(extensions.len() as u8)
+    }
+
+    pub fn get(&self, local_id: u8) -> Option<Arc<dyn Extension>> {
+        let extensions = self.extensions.read().ok()?;
+
+        extensions.get((local_id as usize).checked_sub(1)?).cloned()
+    }
+
+    pub fn all(&self) -> Vec<Arc<dyn Extension>> {
+        self.extensions.read().map(|extensions| extensions.clone()).unwrap_or_default()
+    }
+
+    pub fn handshake(&self, listen_port: u16, peer_ip: IpAddr) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake::new(listen_port, peer_ip);
+
+        for (index, extension) in self.all().iter().enumerate() {
+            handshake.m.insert(extension.name().to_string(), index as i64 + 1);
+            extension.extend_handshake(&mut handshake);
+        }
+
+        handshake
+    }
+}
+
+impl Client {
+    pub fn supports_extensions(&self) -> bool {
+        EXTENSION_PROTOCOL_FLAG.is_set(&self.reserved)
+    }
+
+    pub async fn send_extended_handshake(&mut self, context: &PeerContext) -> SyncResult<()> {
+        if !self.supports_extensions() {
+            return Ok(());
+        }
+
+        let handshake = context.extensions.handshake(context.listen_port, self.peer.ip);
+        let message = Message::format_extended(EXTENDED_HANDSHAKE_ID, &serde_bencode::to_bytes(&handshake)?);
+
+        self.send_message(message).await
+    }
+
+    pub async fn send_extension_message(&mut self, name: &str, payload: &[u8]) -> SyncResult<()> {
+        let extension_id = self.extended.as_ref()
+            .and_then(|handshake| handshake.extension_id(name))
+            .ok_or_else(|| Error::Extension(format!("Peer does not support extension {}", name)))?;
+
+        self.send_message(Message::format_extended(extension_id, payload)).await
+    }
+
+    pub async fn receive_extended(&mut self, message: &Message, context: &PeerContext) -> SyncResult<()> {
+        let (extension_id, payload) = message.parse_extended()?;
+
+        //Peers may send their handshake again to update it
+        if extension_id == EXTENDED_HANDSHAKE_ID {
+            let handshake: ExtendedHandshake = serde_bencode::from_bytes(payload)?;
+
+            for extension in context.extensions.all() {
+                if handshake.extension_id(extension.name()).is_some() {
+                    extension.on_handshake(&self.peer, &handshake)?;
+                }
+            }
+
+            self.extended = Some(handshake);
+            return Ok(());
+        }
+
+        //Messages for an extension we never advertised are ignored
+        let extension = match context.extensions.get(extension_id) {
+            Some(extension) => extension,
+            None => return Ok(()),
+        };
+
+        if let Some(reply) = extension.on_message(&self.peer, payload)? {
+            self.send_extension_message(extension.name(), &reply).await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn close_extensions(&self, context: &PeerContext) {
+        let handshake = match &self.extended {
+            Some(handshake) => handshake,
+            None => return,
+        };
+
+        for extension in context.extensions.all() {
+            if handshake.extension_id(extension.name()).is_some() {
+                extension.on_disconnect(&self.peer);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::net::IpAddr;
+use crate::connection::client::Client;
+use crate::engine::context::PeerContext;
+use crate::error::Error;
+use crate::shared::{SizedBytes, SyncResult};
+use crate::types::bitfield::BitField;
+use crate::types::message::{FAST_FLAG, Message, MessageCode};
+use crate::utils::data::manipulator;
+
+impl Client {
+    pub fn supports_fast(&self) -> bool {
+        FAST_FLAG.is_set(&self.reserved)
+    }
+
+    //Tells the peer which of our pieces it may request even while we choke it
+    pub async fn send_allowed_fast(&mut self, context: &PeerContext) -> SyncResult<()> {
+        //The set is only defined for IPv4 peers
+        let ip = match (self.supports_fast(), self.peer.ip) {
+            (true, IpAddr::V4(ip)) => ip,
+            _ => return Ok(()),
+        };
+
+        let info_hash: SizedBytes = hex::decode(&context.info_hash).ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| Error::MetaInfo("Info hash is not 20 hex encoded bytes".into()))?;
+        let allowed = manipulator::allowed_fast_set(ip, &info_hash, context.piece_count, context.settings.get().allowed_fast_count);
+        let have = context.have.read().map_err(|_| Error::Poisoned("Have bitfield"))?.clone();
+
+        for index in allowed.into_iter().filter(|index| have.has_piece(*index)) {
+            self.granted_fast.insert(index);
+            self.send_message(Message::format_allowed_fast(index)).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn send_reject(&mut self, index: u32, begin: u32, length: u32) -> SyncResult<()> {
+        let message = Message::format_reject(index, begin, length);
+
+        self.send_message(message).await
+    }
+
+    //Reject is handled by the piece download, it is the only one owning the pending requests
+    pub fn receive_fast(&mut self, message: &Message, context: &PeerContext) -> SyncResult<()> {
+        if !self.supports_fast() {
+            return Err(Error::Protocol("Received a fast extension message without negotiating it".into()));
+        }
+
+        match message.id {
+            MessageCode::MessageHaveAll => self.set_bitfield(BitField::full(context.piece_count), context)?,
+            MessageCode::MessageHaveNone => self.set_bitfield(BitField::empty(context.piece_count), context)?,
+            MessageCode::MessageSuggest => {
+                let index = message.parse_have()?;
+                if index >= context.piece_count {
+                    return Err(Error::Protocol("Suggested piece is out of bounds".into()));
+                }
+
+                self.suggested.retain(|suggested| *suggested != index);
+                self.suggested.push(index);
+            },
+            MessageCode::MessageAllowedFast => {
+                let index = message.parse_have()?;
+                if index >= context.piece_count {
+                    return Err(Error::Protocol("Allowed fast piece is out of bounds".into()));
+                }
+
+                self.allowed_fast.insert(index);
+            },
+            _ => return Err(Error::Protocol("Message is not a fast extension message".into())),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use async_channel::Sender;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time;
+use crate::connection::client::Client;
+use crate::engine::context::PeerContext;
+use crate::engine::events::{Event, EventBus};
+use crate::error::Error;
+use crate::settings::SharedSettings;
+use crate::shared::SyncResult;
+use crate::types::message::Handshake;
+use crate::types::peer::Peer;
+
+//Accept errors are retried after a delay doubling up to the maximum, they usually last until connections are closed
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+//Where inbound connections for a given torrent are handed to once the handshake is done
+#[derive(Clone)]
+pub struct InboundTarget {
+    pub context: PeerContext,
+    pub sender: Sender<Client>,
+}
+
+pub struct PeerListener {
+    pub port: u16,
+    pub targets: Arc<RwLock<HashMap<String, InboundTarget>>>,
+    task: JoinHandle<()>,
+}
+
+impl PeerListener {
+    //Peers that connect but never finish their handshake are dropped after the handshake timeout of the settings
+    pub async fn bind(port: u16, settings: Arc<SharedSettings>, events: EventBus) -> SyncResult<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await.map_err(Error::Socket)?;
+        let port = listener.local_addr().map_err(Error::Socket)?.port();
+
+        let targets = Arc::new(RwLock::new(HashMap::new()));
+        let task = tokio::spawn(PeerListener::accept_loop(listener, targets.clone(), settings, events));
+
+        Ok(Self {
+            port,
+            targets,
+            task,
+        })
+    }
+
+    pub fn register(&self, target: InboundTarget) -> SyncResult<()> {
+        let mut targets = self.targets.write().map_err(|_| Error::Poisoned("Listener targets"))?;
+        targets.insert(target.context.info_hash.clone(), target);
+
+        Ok(())
+    }
+
+    pub fn unregister(&self, info_hash: &str) -> SyncResult<()> {
+        let mut targets = self.targets.write().map_err(|_| Error::Poisoned("Listener targets"))?;
+        targets.remove(info_hash);
+
+        Ok(())
+    }
+
+    async fn accept_loop(listener: TcpListener, targets: Arc<RwLock<HashMap<String, InboundTarget>>>, settings: Arc<SharedSettings>, events: EventBus) {
+        let mut backoff = ACCEPT_BACKOFF;
+
+        loop {
+            let (connection, address) = match listener.accept().await {
+                Ok(accepted) => {
+                    backoff = ACCEPT_BACKOFF;
+                    accepted
+                },
+                Err(error) => {
+                    events.emit(Event::ListenerError { message: error.to_string() });
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+
+            let targets = targets.clone();
+            let timeout = settings.get().handshake_timeout();
+            //Peers failing the handshake are dropped, the accepted ones are reported by the torrent they belong to
+            tokio::spawn(async move {
+                let _ = time::timeout(timeout, PeerListener::accept_peer(connection, address, targets)).await;
+            });
+        }
+    }
+
+    async fn accept_peer(mut connection: TcpStream, address: SocketAddr, targets: Arc<RwLock<HashMap<String, InboundTarget>>>) -> SyncResult<()> {
+        let handshake = Client::read_handshake(&mut connection).await?;
+        if handshake.pstr != "BitTorrent protocol" {
+            return Err(Error::Protocol("Invalid pstr in handshake".into()));
+        }
+
+        let target = targets.read().map_err(|_| Error::Poisoned("Listener targets"))?.get(&handshake.info_hash).cloned();
+        let target = match target {
+            Some(target) => target,
+            None => {
+                connection.shutdown().await.map_err(Error::Network)?;
+                return Err(Error::Protocol("Handshake for a torrent we do not serve".into()));
+            }
+        };
+
+        let reply = Handshake::new(handshake.info_hash.clone(), target.context.peer_id);
+        connection.write_all(&reply.to_bytes()?).await.map_err(Error::Network)?;
+
+        //The reply lets our dialing side see it reached itself, so that it never tries again
+        if handshake.peer_id == target.context.peer_id {
+            return Err(Error::Refused("Connection from ourselves"));
+        }
+
+        let peer = Peer::new(address.ip(), address.port());
+        let mut client = Client::from_connection(connection, peer, &handshake, &target.context);
+        client.send_bitfield(&target.context).await?;
+        client.send_extended_handshake(&target.context).await?;
+        client.send_allowed_fast(&target.context).await?;
+
+        target.sender.send(client).await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for PeerListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use futures_util::future;
+use sha1::{Digest, Sha1};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time;
+use crate::connection::client::Client;
+use crate::connection::extension::Extension;
+use crate::error::Error;
+use crate::serializer::scanner;
+use crate::shared::{SizedBytes, SyncResult};
+use crate::types::extension::{self, ExtendedHandshake, MetadataMessage};
+use crate::types::message::{EXTENSION_PROTOCOL_FLAG, Handshake, Message, MessageCode};
+use crate::types::peer::Peer;
+
+//Id peers must use when sending us ut_metadata messages
+const LOCAL_METADATA_ID: u8 = 1;
+//Info dictionaries are rarely above a few hundred KiB, anything this large is a broken or hostile peer
+const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+//Peers asked at the same time, the first full and valid answer wins
+const PARALLEL_PEERS: usize = 8;
+
+//Fetches the info dictionary of a magnet link from peers (BEP 9)
+pub struct MetadataDownloader {
+    pub info_hash: String,
+    pub peer_id: SizedBytes,
+}
+
+impl MetadataDownloader {
+    pub fn new(info_hash: String, peer_id: SizedBytes) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub async fn fetch_from_peers(&self, peers: &[Peer]) -> SyncResult<Vec<u8>> {
+        for batch in peers.chunks(PARALLEL_PEERS) {
+            let fetches = batch.iter().map(|peer| Box::pin(self.fetch(peer)));
+
+            //No peer of the batch had it, the next batch gets its chance
+            if let Ok((metadata, _)) = future::select_ok(fetches).await {
+                return Ok(metadata);
+            }
+        }
+
+        Err(Error::MetaInfo("No peer could provide the metadata".into()))
+    }
+
+    pub async fn fetch(&self, peer: &Peer) -> SyncResult<Vec<u8>> {
+        let metadata = time::timeout(METADATA_TIMEOUT, self.exchange(peer)).await
+            .map_err(|_| Error::PeerTimeout("Metadata exchange"))??;
+
+        let info_hash = hex::encode(Sha1::digest(&metadata));
+        if info_hash != self.info_hash {
+            return Err(Error::Protocol("Metadata does not match the info hash".into()));
+        }
+
+        Ok(metadata)
+    }
+
+    async fn exchange(&self, peer: &Peer) -> SyncResult<Vec<u8>> {
+        let mut connection = TcpStream::connect(SocketAddr::new(peer.ip, peer.port)).await.map_err(Error::Network)?;
+
+        let handshake = Handshake::new(self.info_hash.clone(), self.peer_id);
+        connection.write_all(&handshake.to_bytes()?).await.map_err(Error::Network)?;
+
+        let reply = Client::read_handshake(&mut connection).await?;
+        if reply.info_hash != self.info_hash {
+            return Err(Error::Protocol("Invalid info_hash in handshake".into()));
+        }
+
+        if !reply.has_flag(EXTENSION_PROTOCOL_FLAG) {
+            return Err(Error::Protocol("Peer does not support the extension protocol".into()));
+        }
+
+        let ours = ExtendedHandshake {
+            m: BTreeMap::from([(extension::METADATA_EXTENSION.to_string(), LOCAL_METADATA_ID as i64)]),
+            ..Default::default()
+        };
+        let message = Message::format_extended(extension::EXTENDED_HANDSHAKE_ID, &serde_bencode::to_bytes(&ours)?);
+        connection.write_all(&message.to_bytes()?).await.map_err(Error::Network)?;
+
+        let theirs = MetadataDownloader::receive_extended_handshake(&mut connection).await?;
+        let remote_id = theirs.extension_id(extension::METADATA_EXTENSION).ok_or_else(|| Error::Protocol("Peer does not support ut_metadata".into()))?;
+        let size = match theirs.metadata_size {
+            Some(size) if size > 0 && size <= MAX_METADATA_SIZE => size,
+            _ => return Err(Error::Protocol("Peer announced an invalid metadata size".into())),
+        };
+
+        let mut metadata = vec![0; size as usize];
+        for (piece, block) in metadata.chunks_mut(extension::METADATA_BLOCK_SIZE as usize).enumerate() {
+            let request = MetadataMessage {
+                msg_type: extension::METADATA_REQUEST,
+                piece: piece as u32,
+                total_size: None,
+            };
+            let message = Message::format_extended(remote_id, &serde_bencode::to_bytes(&request)?);
+            connection.write_all(&message.to_bytes()?).await.map_err(Error::Network)?;
+
+            let data = MetadataDownloader::receive_metadata_piece(&mut connection, piece as u32).await?;
+            if data.len() != block.len() {
+                return Err(Error::Protocol("Metadata piece has an invalid length".into()));
+            }
+
+            block.copy_from_slice(&data);
+        }
+
+        Ok(metadata)
+    }
+
+    //Other messages like bitfield or have may arrive first, they are not needed here
+    async fn receive_extended_handshake(connection: &mut TcpStream) -> SyncResult<ExtendedHandshake> {
+        loop {
+            let message = Client::static_read_message(connection).await?;
+            if message.id != MessageCode::MessageExtended {
+                continue;
+            }
+
+            let (extension_id, payload) = message.parse_extended()?;
+            if extension_id == extension::EXTENDED_HANDSHAKE_ID {
+                return Ok(serde_bencode::from_bytes(payload)?);
+            }
+        }
+    }
+
+    async fn receive_metadata_piece(connection: &mut TcpStream, piece: u32) -> SyncResult<Vec<u8>> {
+        loop {
+            let message = Client::static_read_message(connection).await?;
+            if message.id != MessageCode::MessageExtended {
+                continue;
+            }
+
+            let (extension_id, payload) = message.parse_extended()?;
+            if extension_id != LOCAL_METADATA_ID {
+                continue;
+            }
+
+            //The data of a piece follows its bencoded dictionary
+            let end = scanner::skip_value(payload, 0)?;
+            let header: MetadataMessage = serde_bencode::from_bytes(&payload[..end])?;
+
+            match header.msg_type {
+                extension::METADATA_REJECT => return Err(Error::Protocol("Peer rejected the metadata request".into())),
+                extension::METADATA_DATA if header.piece == piece => return Ok(payload[end..].to_vec()),
+                _ => {},
+            }
+        }
+    }
+}
+
+//Serves our info dictionary to peers resolving a magnet link
+pub struct MetadataProvider {
+    pub info_bytes: Vec<u8>,
+}
+
+impl MetadataProvider {
+    pub fn new(info_bytes: Vec<u8>) -> Self {
+        Self {
+            info_bytes,
+        }
+    }
+}
+
+impl Extension for MetadataProvider {
+    fn name(&self) -> &str {
+        extension::METADATA_EXTENSION
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = Some(self.info_bytes.len() as u64);
+    }
+
+    fn on_message(&self, _peer: &Peer, payload: &[u8]) -> SyncResult<Option<Vec<u8>>> {
+        let end = scanner::skip_value(payload, 0)?;
+        let request: MetadataMessage = serde_bencode::from_bytes(&payload[..end])?;
+        if request.msg_type != extension::METADATA_REQUEST {
+            return Ok(None);
+        }
+
+        let start = request.piece as usize * extension::METADATA_BLOCK_SIZE as usize;
+        if start >= self.info_bytes.len() {
+            let reject = MetadataMessage {
+                msg_type: extension::METADATA_REJECT,
+                piece: request.piece,
+                total_size: None,
+            };
+
+            return Ok(Some(serde_bencode::to_bytes(&reject)?));
+        }
+
+        let end = (start + extension::METADATA_BLOCK_SIZE as usize).min(self.info_bytes.len());
+        let data = MetadataMessage {
+            msg_type: extension::METADATA_DATA,
+            piece: request.piece,
+            total_size: Some(self.info_bytes.len() as u64),
+        };
+
+        let mut reply = serde_bencode::to_bytes(&data)?;
+        reply.extend_from_slice(&self.info_bytes[start..end]);
+
+        Ok(Some(reply))
+    }
+}
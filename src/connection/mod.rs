@@ -1 +1,6 @@
-pub mod client;
\ No newline at end of file
+pub mod bandwidth;
+pub mod client;
+pub mod extension;
+pub mod fast;
+pub mod listener;
+pub mod metadata;
\ No newline at end of file
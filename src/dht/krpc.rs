@@ -0,0 +1,163 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use serde_derive::{Serialize, Deserialize};
+use crate::error::Error;
+use crate::shared::{SizedBytes, SyncResult};
+
+//Compact node info is the node id followed by its IPv4 address and port
+pub const COMPACT_NODE_SIZE: usize = 26;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KrpcMessage {
+    #[serde(rename = "t")]
+    pub transaction_id: ByteBuf,
+    //"q" for queries, "r" for responses and "e" for errors
+    #[serde(rename = "y")]
+    pub kind: String,
+
+    #[serde(rename = "q", skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    #[serde(rename = "a", skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<KrpcArguments>,
+    #[serde(rename = "r", skip_serializing_if = "Option::is_none")]
+    pub response: Option<KrpcResponse>,
+    #[serde(rename = "e", skip_serializing_if = "Option::is_none")]
+    pub error: Option<Vec<Value>>,
+
+    #[serde(rename = "v", skip_serializing_if = "Option::is_none")]
+    pub version: Option<ByteBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KrpcArguments {
+    pub id: ByteBuf,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KrpcResponse {
+    pub id: ByteBuf,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+impl KrpcMessage {
+    pub fn query(transaction_id: &[u8], query: &str, arguments: KrpcArguments) -> KrpcMessage {
+        KrpcMessage {
+            transaction_id: ByteBuf::from(transaction_id),
+            kind: "q".to_string(),
+            query: Some(query.to_string()),
+            arguments: Some(arguments),
+            response: None,
+            error: None,
+            version: None,
+        }
+    }
+
+    pub fn response(transaction_id: &[u8], response: KrpcResponse) -> KrpcMessage {
+        KrpcMessage {
+            transaction_id: ByteBuf::from(transaction_id),
+            kind: "r".to_string(),
+            query: None,
+            arguments: None,
+            response: Some(response),
+            error: None,
+            version: None,
+        }
+    }
+
+    pub fn error(transaction_id: &[u8], code: i64, message: &str) -> KrpcMessage {
+        KrpcMessage {
+            transaction_id: ByteBuf::from(transaction_id),
+            kind: "e".to_string(),
+            query: None,
+            arguments: None,
+            response: None,
+            error: Some(vec![Value::Int(code), Value::Bytes(message.as_bytes().to_vec())]),
+            version: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> SyncResult<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> SyncResult<KrpcMessage> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    pub fn error_message(&self) -> String {
+        match self.error.as_deref() {
+            Some([Value::Int(code), Value::Bytes(message)]) => format!("{} {}", code, String::from_utf8_lossy(message)),
+            _ => "Malformed error".to_string(),
+        }
+    }
+}
+
+pub fn node_id(bytes: &[u8]) -> SyncResult<SizedBytes> {
+    bytes.try_into().map_err(|_| Error::Dht("Node id is not 20 bytes long".into()))
+}
+
+pub fn encode_nodes(nodes: &[(SizedBytes, SocketAddr)]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_SIZE);
+
+    //Only IPv4 nodes fit in the compact format of BEP 5
+    for (id, address) in nodes {
+        if let SocketAddr::V4(address) = address {
+            bytes.extend_from_slice(id);
+            bytes.extend_from_slice(&address.ip().octets());
+            bytes.extend_from_slice(&address.port().to_be_bytes());
+        }
+    }
+
+    bytes
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> SyncResult<Vec<(SizedBytes, SocketAddr)>> {
+    if !bytes.len().is_multiple_of(COMPACT_NODE_SIZE) {
+        return Err(Error::Dht("Compact nodes length is not a multiple of 26".into()));
+    }
+
+    let mut nodes = Vec::with_capacity(bytes.len() / COMPACT_NODE_SIZE);
+    for chunk in bytes.chunks_exact(COMPACT_NODE_SIZE) {
+        let id = node_id(&chunk[0..20])?;
+        let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
+        let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+
+        nodes.push((id, SocketAddr::new(IpAddr::V4(ip), port)));
+    }
+
+    Ok(nodes)
+}
+
+pub fn encode_peer(address: &SocketAddr) -> Option<ByteBuf> {
+    match address {
+        SocketAddr::V4(address) => {
+            let mut bytes = address.ip().octets().to_vec();
+            bytes.extend_from_slice(&address.port().to_be_bytes());
+
+            Some(ByteBuf::from(bytes))
+        },
+        SocketAddr::V6(_) => None,
+    }
+}
@@ -0,0 +1,582 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures_util::future;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time;
+use crate::dht::krpc::{self, KrpcArguments, KrpcMessage, KrpcResponse};
+use crate::dht::routing::{self, RoutingTable, BUCKET_SIZE};
+use crate::engine::events::{Event, EventBus};
+use crate::error::Error;
+use crate::shared::{SizedBytes, SyncResult};
+use crate::types::peer::Peer;
+
+//Queries sent in parallel during a lookup, the alpha of Kademlia
+const ALPHA: usize = 3;
+const MAX_LOOKUP_ROUNDS: usize = 32;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+//Tokens are valid for the current and the previous secret, so between 5 and 10 minutes
+const SECRET_LIFETIME: Duration = Duration::from_secs(5 * 60);
+//Announced peers are forgotten after this delay unless they announce again
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_TORRENT: usize = 200;
+//Receive errors are retried after a delay doubling up to the maximum, a broken socket must not spin the loop
+const RECEIVE_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(10);
+
+//Transaction id of a query and the node it was sent to
+type PendingQuery = (Vec<u8>, SocketAddr);
+
+pub struct DhtNode {
+    pub id: SizedBytes,
+    state: Arc<DhtState>,
+    task: JoinHandle<()>,
+}
+
+struct DhtState {
+    id: SizedBytes,
+    socket: UdpSocket,
+
+    routing: Mutex<RoutingTable>,
+    //Queries waiting for their reply, by transaction id and queried node, other nodes cannot answer in their place
+    pending: Mutex<HashMap<PendingQuery, oneshot::Sender<KrpcMessage>>>,
+    next_transaction: AtomicU16,
+    events: EventBus,
+
+    //Peers other nodes announced to us, by info hash
+    announced: Mutex<HashMap<SizedBytes, Vec<(SocketAddr, Instant)>>>,
+    secrets: Mutex<TokenSecrets>,
+}
+
+struct TokenSecrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+//A node that answered a lookup, with the token it handed out for announces
+pub struct LookupNode {
+    pub id: SizedBytes,
+    pub address: SocketAddr,
+    pub token: Option<ByteBuf>,
+}
+
+impl DhtNode {
+    pub async fn bind(address: SocketAddr, events: EventBus) -> SyncResult<Self> {
+        let socket = UdpSocket::bind(address).await.map_err(Error::Socket)?;
+        let id: SizedBytes = rand::random();
+
+        let state = Arc::new(DhtState {
+            id,
+            socket,
+
+            routing: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            events,
+
+            announced: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(TokenSecrets {
+                current: rand::random(),
+                previous: rand::random(),
+                rotated: Instant::now(),
+            }),
+        });
+
+        let task = tokio::spawn(DhtState::receive_loop(state.clone()));
+
+        Ok(Self {
+            id,
+            state,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SyncResult<SocketAddr> {
+        self.state.socket.local_addr().map_err(Error::Socket)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.state.routing.lock().map(|routing| routing.len()).unwrap_or(0)
+    }
+
+    //Joins the network through known nodes, returns the size of the routing table afterwards
+    pub async fn bootstrap(&self, nodes: &[SocketAddr]) -> SyncResult<usize> {
+        let pings = nodes.iter().map(|address| self.state.ping(*address));
+        future::join_all(pings).await;
+
+        if self.node_count() == 0 {
+            return Err(Error::Dht("No bootstrap node answered".into()));
+        }
+
+        //Looking ourselves up fills the buckets close to our own id
+        self.state.lookup(self.id, false).await;
+
+        Ok(self.node_count())
+    }
+
+    pub async fn ping(&self, address: SocketAddr) -> SyncResult<SizedBytes> {
+        self.state.ping(address).await
+    }
+
+    pub async fn get_peers(&self, info_hash: SizedBytes) -> SyncResult<Vec<Peer>> {
+        let (_nodes, peers) = self.state.lookup(info_hash, true).await;
+
+        Ok(peers)
+    }
+
+    //Finds peers for the torrent and tells the closest nodes that we are one of them
+    pub async fn announce(&self, info_hash: SizedBytes, port: u16) -> SyncResult<Vec<Peer>> {
+        let (nodes, peers) = self.state.lookup(info_hash, true).await;
+
+        let announces = nodes.into_iter()
+            .filter_map(|node| node.token.map(|token| (node.address, token)))
+            .map(|(address, token)| {
+                let arguments = KrpcArguments {
+                    id: ByteBuf::from(self.id),
+                    info_hash: Some(ByteBuf::from(info_hash)),
+                    port: Some(port),
+                    token: Some(token),
+                    ..Default::default()
+                };
+
+                self.state.query(address, "announce_peer", arguments)
+            });
+
+        future::join_all(announces).await;
+
+        Ok(peers)
+    }
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl DhtState {
+    async fn receive_loop(state: Arc<DhtState>) {
+        let mut buffer = vec![0; 65536];
+        let mut backoff = RECEIVE_BACKOFF;
+
+        loop {
+            let (length, from) = match state.socket.recv_from(&mut buffer).await {
+                Ok(received) => {
+                    backoff = RECEIVE_BACKOFF;
+                    received
+                },
+                Err(error) => {
+                    state.events.emit(Event::DhtError { info_hash: None, message: error.to_string() });
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RECEIVE_BACKOFF);
+                    continue;
+                }
+            };
+
+            let message = match KrpcMessage::from_bytes(&buffer[..length]) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+
+            if message.kind == "q" {
+                let reply = state.handle_query(&message, from);
+                if let Ok(bytes) = reply.to_bytes() {
+                    let _ = state.socket.send_to(&bytes, from).await;
+                }
+
+                continue;
+            }
+
+            let key = (message.transaction_id.to_vec(), from);
+            let pending = state.pending.lock().ok().and_then(|mut pending| pending.remove(&key));
+            if let Some(sender) = pending {
+                let _ = sender.send(message);
+            }
+        }
+    }
+
+    fn handle_query(&self, message: &KrpcMessage, from: SocketAddr) -> KrpcMessage {
+        let transaction_id = message.transaction_id.as_ref();
+        let (query, arguments) = match (&message.query, &message.arguments) {
+            (Some(query), Some(arguments)) => (query.as_str(), arguments),
+            _ => return KrpcMessage::error(transaction_id, krpc::ERROR_PROTOCOL, "Missing query or arguments"),
+        };
+
+        let sender_id = match krpc::node_id(&arguments.id) {
+            Ok(id) => id,
+            Err(_) => return KrpcMessage::error(transaction_id, krpc::ERROR_PROTOCOL, "Invalid node id"),
+        };
+
+        if let Ok(mut routing) = self.routing.lock() {
+            routing.insert(sender_id, from);
+        }
+
+        let mut response = KrpcResponse {
+            id: ByteBuf::from(self.id),
+            ..Default::default()
+        };
+
+        match query {
+            "ping" => {},
+            "find_node" => {
+                let target = match arguments.target.as_ref().map(|bytes| krpc::node_id(bytes)) {
+                    Some(Ok(target)) => target,
+                    _ => return KrpcMessage::error(transaction_id, krpc::ERROR_PROTOCOL, "Missing target"),
+                };
+
+                response.nodes = Some(ByteBuf::from(self.closest_nodes(&target)));
+            },
+            "get_peers" => {
+                let info_hash = match arguments.info_hash.as_ref().map(|bytes| krpc::node_id(bytes)) {
+                    Some(Ok(info_hash)) => info_hash,
+                    _ => return KrpcMessage::error(transaction_id, krpc::ERROR_PROTOCOL, "Missing info_hash"),
+                };
+
+                response.token = Some(ByteBuf::from(self.token(&from, false)));
+
+                let values = self.announced_peers(&info_hash);
+                if values.is_empty() {
+                    response.nodes = Some(ByteBuf::from(self.closest_nodes(&info_hash)));
+                } else {
+                    response.values = Some(values);
+                }
+            },
+            "announce_peer" => {
+                let (info_hash, token) = match (arguments.info_hash.as_ref().map(|bytes| krpc::node_id(bytes)), &arguments.token) {
+                    (Some(Ok(info_hash)), Some(token)) => (info_hash, token),
+                    _ => return KrpcMessage::error(transaction_id, krpc::ERROR_PROTOCOL, "Missing info_hash or token"),
+                };
+
+                if !self.is_valid_token(&from, token) {
+                    return KrpcMessage::error(transaction_id, krpc::ERROR_PROTOCOL, "Bad token");
+                }
+
+                //With implied_port the peer port is the one the query came from, useful behind NATs
+                let port = match (arguments.implied_port, arguments.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return KrpcMessage::error(transaction_id, krpc::ERROR_PROTOCOL, "Missing port"),
+                };
+
+                self.store_peer(info_hash, SocketAddr::new(from.ip(), port));
+            },
+            _ => return KrpcMessage::error(transaction_id, krpc::ERROR_METHOD_UNKNOWN, "Method Unknown"),
+        }
+
+        KrpcMessage::response(transaction_id, response)
+    }
+
+    async fn query(&self, address: SocketAddr, query: &str, arguments: KrpcArguments) -> SyncResult<KrpcResponse> {
+        let transaction_id = self.next_transaction.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+        let (sender, receiver) = oneshot::channel();
+
+        let key = (transaction_id.clone(), address);
+        self.pending.lock().map_err(|_| Error::Poisoned("Dht pending"))?.insert(key.clone(), sender);
+
+        let message = KrpcMessage::query(&transaction_id, query, arguments);
+        self.socket.send_to(&message.to_bytes()?, address).await.map_err(Error::Socket)?;
+
+        let reply = match time::timeout(QUERY_TIMEOUT, receiver).await {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                self.pending.lock().map_err(|_| Error::Poisoned("Dht pending"))?.remove(&key);
+                self.routing.lock().map_err(|_| Error::Poisoned("Dht routing"))?.mark_failed(&address);
+
+                return Err(Error::Timeout("Dht query"));
+            }
+        };
+
+        if reply.kind == "e" {
+            return Err(Error::Dht(reply.error_message()));
+        }
+
+        let response = reply.response.ok_or_else(|| Error::Dht("Dht reply without response".into()))?;
+        let id = krpc::node_id(&response.id)?;
+        self.routing.lock().map_err(|_| Error::Poisoned("Dht routing"))?.insert(id, address);
+
+        Ok(response)
+    }
+
+    async fn ping(&self, address: SocketAddr) -> SyncResult<SizedBytes> {
+        let arguments = KrpcArguments {
+            id: ByteBuf::from(self.id),
+            ..Default::default()
+        };
+        let response = self.query(address, "ping", arguments).await?;
+
+        krpc::node_id(&response.id)
+    }
+
+    //Iterative Kademlia lookup, returns the closest nodes that answered and the peers they knew
+    async fn lookup(&self, target: SizedBytes, want_peers: bool) -> (Vec<LookupNode>, Vec<Peer>) {
+        let mut candidates = self.routing.lock().map(|routing| routing.closest(&target, BUCKET_SIZE)).unwrap_or_default();
+        let mut queried = HashSet::new();
+        let mut answered = Vec::new();
+        let mut peers = Vec::new();
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            candidates.sort_by_key(|(id, _)| routing::distance(id, &target));
+
+            let batch = candidates.iter()
+                .take(BUCKET_SIZE)
+                .filter(|(_, address)| !queried.contains(address))
+                .take(ALPHA)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if batch.is_empty() {
+                break;
+            }
+
+            let queries = batch.iter().map(|(_, address)| {
+                let mut arguments = KrpcArguments {
+                    id: ByteBuf::from(self.id),
+                    ..Default::default()
+                };
+
+                let query = if want_peers {
+                    arguments.info_hash = Some(ByteBuf::from(target));
+                    "get_peers"
+                } else {
+                    arguments.target = Some(ByteBuf::from(target));
+                    "find_node"
+                };
+
+                self.query(*address, query, arguments)
+            });
+            let results = future::join_all(queries).await;
+
+            for ((id, address), result) in batch.into_iter().zip(results) {
+                queried.insert(address);
+
+                let response = match result {
+                    Ok(response) => response,
+                    Err(_) => {
+                        candidates.retain(|(_, candidate)| *candidate != address);
+                        continue;
+                    }
+                };
+
+                if let Some(nodes) = response.nodes.as_deref().and_then(|nodes| krpc::decode_nodes(nodes).ok()) {
+                    for node in nodes {
+                        let is_known = candidates.iter().any(|(_, candidate)| *candidate == node.1);
+                        if node.0 != self.id && !is_known {
+                            candidates.push(node);
+                        }
+                    }
+                }
+
+                for value in response.values.iter().flatten() {
+                    for peer in Peer::from_bytes(value).unwrap_or_default() {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                }
+
+                answered.push(LookupNode {
+                    id,
+                    address,
+                    token: response.token,
+                });
+            }
+        }
+
+        answered.sort_by_key(|node| routing::distance(&node.id, &target));
+        answered.truncate(BUCKET_SIZE);
+
+        (answered, peers)
+    }
+
+    fn closest_nodes(&self, target: &SizedBytes) -> Vec<u8> {
+        let nodes = self.routing.lock().map(|routing| routing.closest(target, BUCKET_SIZE)).unwrap_or_default();
+
+        krpc::encode_nodes(&nodes)
+    }
+
+    fn token(&self, address: &SocketAddr, previous: bool) -> Vec<u8> {
+        let secret = match self.secrets.lock() {
+            Ok(mut secrets) => {
+                if secrets.rotated.elapsed() > SECRET_LIFETIME {
+                    secrets.previous = secrets.current;
+                    secrets.current = rand::random();
+                    secrets.rotated = Instant::now();
+                }
+
+                if previous { secrets.previous } else { secrets.current }
+            },
+            Err(_) => return Vec::new(),
+        };
+
+        let mut hasher = Sha1::new();
+        match address.ip() {
+            std::net::IpAddr::V4(ip) => hasher.update(ip.octets()),
+            std::net::IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(secret);
+
+        hasher.finalize().to_vec()
+    }
+
+    fn is_valid_token(&self, address: &SocketAddr, token: &[u8]) -> bool {
+        !token.is_empty() && (self.token(address, false) == token || self.token(address, true) == token)
+    }
+
+    fn store_peer(&self, info_hash: SizedBytes, address: SocketAddr) {
+        let mut announced = match self.announced.lock() {
+            Ok(announced) => announced,
+            Err(_) => return,
+        };
+
+        let peers = announced.entry(info_hash).or_default();
+        peers.retain(|(peer, announced_at)| *peer != address && announced_at.elapsed() < PEER_LIFETIME);
+
+        if peers.len() < MAX_PEERS_PER_TORRENT {
+            peers.push((address, Instant::now()));
+        }
+    }
+
+    fn announced_peers(&self, info_hash: &SizedBytes) -> Vec<ByteBuf> {
+        let announced = match self.announced.lock() {
+            Ok(announced) => announced,
+            Err(_) => return Vec::new(),
+        };
+
+        announced.get(info_hash)
+            .map(|peers| {
+                peers.iter()
+                    .filter(|(_, announced_at)| announced_at.elapsed() < PEER_LIFETIME)
+                    .filter_map(|(address, _)| krpc::encode_peer(address))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use super::*;
+
+    async fn node() -> DhtNode {
+        DhtNode::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), EventBus::new()).await.unwrap()
+    }
+
+    //Nodes joining one after the other through the first one
+    async fn network(size: usize) -> Vec<DhtNode> {
+        let mut nodes = Vec::with_capacity(size);
+        for _ in 0..size {
+            nodes.push(node().await);
+        }
+
+        let first = nodes[0].local_addr().unwrap();
+        nodes[0].bootstrap(&[nodes[1].local_addr().unwrap()]).await.unwrap();
+        for node in nodes.iter().skip(1) {
+            node.bootstrap(&[first]).await.unwrap();
+        }
+
+        nodes
+    }
+
+    fn announce_arguments(node: &DhtNode, info_hash: SizedBytes, token: Option<ByteBuf>) -> KrpcArguments {
+        KrpcArguments {
+            id: ByteBuf::from(node.id),
+            info_hash: Some(ByteBuf::from(info_hash)),
+            port: Some(7000),
+            token,
+            ..Default::default()
+        }
+    }
+
+    async fn token(from: &DhtNode, to: &DhtNode, info_hash: SizedBytes) -> ByteBuf {
+        let arguments = KrpcArguments {
+            id: ByteBuf::from(from.id),
+            info_hash: Some(ByteBuf::from(info_hash)),
+            ..Default::default()
+        };
+        let response = from.state.query(to.local_addr().unwrap(), "get_peers", arguments).await.unwrap();
+
+        response.token.unwrap()
+    }
+
+    #[tokio::test]
+    async fn bootstrap_fills_routing_tables() {
+        let nodes = network(8).await;
+
+        for node in nodes.iter() {
+            assert!(node.node_count() >= 2, "{} nodes known", node.node_count());
+        }
+        assert_eq!(nodes[0].node_count(), 7);
+    }
+
+    #[tokio::test]
+    async fn get_peers_finds_announced_peers() {
+        let nodes = network(8).await;
+        let info_hash: SizedBytes = rand::random();
+
+        let peers = nodes[3].announce(info_hash, 7000).await.unwrap();
+        assert!(peers.is_empty());
+
+        for node in nodes.iter().filter(|node| node.id != nodes[3].id) {
+            let peers = node.get_peers(info_hash).await.unwrap();
+            assert_eq!(peers, vec![Peer::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 7000)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_peer_checks_the_token() {
+        let (a, b) = (node().await, node().await);
+        let info_hash: SizedBytes = rand::random();
+        let address = b.local_addr().unwrap();
+
+        let bad = a.state.query(address, "announce_peer", announce_arguments(&a, info_hash, Some(ByteBuf::from(vec![1; 20])))).await;
+        assert!(matches!(bad, Err(Error::Dht(message)) if message.contains("Bad token")));
+        assert!(b.state.announced_peers(&info_hash).is_empty());
+
+        let token = token(&a, &b, info_hash).await;
+        a.state.query(address, "announce_peer", announce_arguments(&a, info_hash, Some(token))).await.unwrap();
+
+        let expected = krpc::encode_peer(&SocketAddr::from((Ipv4Addr::LOCALHOST, 7000))).unwrap();
+        assert_eq!(b.state.announced_peers(&info_hash), vec![expected]);
+    }
+
+    #[tokio::test]
+    async fn announce_peer_with_implied_port() {
+        let (a, b) = (node().await, node().await);
+        let info_hash: SizedBytes = rand::random();
+
+        let token = token(&a, &b, info_hash).await;
+        let mut arguments = announce_arguments(&a, info_hash, Some(token));
+        arguments.implied_port = Some(1);
+        a.state.query(b.local_addr().unwrap(), "announce_peer", arguments).await.unwrap();
+
+        //The port the query came from wins over the one given
+        let expected = krpc::encode_peer(&a.local_addr().unwrap()).unwrap();
+        assert_eq!(b.state.announced_peers(&info_hash), vec![expected]);
+    }
+
+    #[tokio::test]
+    async fn replies_must_come_from_the_queried_node() {
+        let (a, b) = (node().await, node().await);
+        let intruder = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+
+        //The intruder answers the ping with its transaction id, before b does
+        let a_address = a.local_addr().unwrap();
+        let transaction = a.state.next_transaction.load(Ordering::Relaxed).to_be_bytes();
+        let forged = KrpcMessage::response(&transaction, KrpcResponse { id: ByteBuf::from([0; 20]), ..Default::default() });
+        let query = a.ping(b.local_addr().unwrap());
+        let forge = async {
+            intruder.send_to(&forged.to_bytes().unwrap(), a_address).await.unwrap();
+        };
+        let (id, _) = tokio::join!(query, forge);
+
+        assert_eq!(id.unwrap(), b.id);
+    }
+}
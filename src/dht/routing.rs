@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use crate::shared::SizedBytes;
+
+//Nodes per bucket, the K of Kademlia
+pub const BUCKET_SIZE: usize = 8;
+//A node we have not heard from for this long may be replaced by a new one
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+//Nodes failing this many queries in a row are considered bad
+const MAX_FAILURES: u32 = 2;
+
+#[derive(Debug, Clone)]
+pub struct NodeEntry {
+    pub id: SizedBytes,
+    pub address: SocketAddr,
+    pub last_seen: Instant,
+    pub failures: u32,
+}
+
+pub struct RoutingTable {
+    pub own_id: SizedBytes,
+    //Bucket n holds the nodes sharing exactly n leading bits with our own id
+    pub buckets: Vec<Vec<NodeEntry>>,
+}
+
+pub fn distance(first: &SizedBytes, second: &SizedBytes) -> SizedBytes {
+    let mut distance = [0; 20];
+
+    for (index, byte) in distance.iter_mut().enumerate() {
+        *byte = first[index] ^ second[index];
+    }
+
+    distance
+}
+
+impl NodeEntry {
+    pub fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    pub fn is_questionable(&self) -> bool {
+        self.last_seen.elapsed() > QUESTIONABLE_AFTER
+    }
+}
+
+impl RoutingTable {
+    pub fn new(own_id: SizedBytes) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn bucket_index(&self, id: &SizedBytes) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+
+        let mut shared_bits = 0;
+        for byte in distance.iter() {
+            if *byte == 0 {
+                shared_bits += 8;
+                continue;
+            }
+
+            shared_bits += byte.leading_zeros() as usize;
+            return Some(shared_bits);
+        }
+
+        //Our own id has no bucket
+        None
+    }
+
+    //Records a node that just talked to us, returns false when its bucket had no room for it
+    pub fn insert(&mut self, id: SizedBytes, address: SocketAddr) -> bool {
+        let index = match self.bucket_index(&id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.id == id) {
+            entry.address = address;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            return true;
+        }
+
+        let entry = NodeEntry {
+            id,
+            address,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+
+        if bucket.len() < BUCKET_SIZE {
+            bucket.push(entry);
+            return true;
+        }
+
+        //Full bucket, only bad or long silent nodes make room for new ones
+        let replaceable = bucket.iter().position(|entry| entry.is_bad())
+            .or_else(|| bucket.iter().position(|entry| entry.is_questionable()));
+
+        match replaceable {
+            Some(position) => {
+                bucket[position] = entry;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn mark_failed(&mut self, address: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            if let Some(entry) = bucket.iter_mut().find(|entry| entry.address == *address) {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    pub fn closest(&self, target: &SizedBytes, count: usize) -> Vec<(SizedBytes, SocketAddr)> {
+        let mut nodes = self.buckets.iter()
+            .flatten()
+            .filter(|entry| !entry.is_bad())
+            .map(|entry| (entry.id, entry.address))
+            .collect::<Vec<_>>();
+
+        nodes.sort_by_key(|(id, _)| distance(id, target));
+        nodes.truncate(count);
+
+        nodes
+    }
+}
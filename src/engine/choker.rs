@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use async_channel::{Receiver, Sender};
+use rand::seq::SliceRandom;
+use tokio::time::{self, Instant};
+use crate::engine::picker::PiecePicker;
+use crate::error::Error;
+use crate::settings::SharedSettings;
+use crate::shared::SyncResult;
+use crate::types::peer::Peer;
+
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+//The optimistic unchoke moves on every third rechoke
+const OPTIMISTIC_ROUNDS: u32 = 3;
+//A peer we download from that sent nothing for that long is snubbing us
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChokerCommand {
+    Choke,
+    Unchoke,
+}
+
+//Transfer state of a connection, updated by its task and read by the choker
+pub struct PeerSlot {
+    pub peer: Peer,
+    //Bytes received from and sent to the peer
+    pub downloaded: AtomicU64,
+    pub uploaded: AtomicU64,
+    pub interested: AtomicBool,
+    //Whether the choker wants the peer unchoked
+    pub unchoked: AtomicBool,
+
+    last_block: Mutex<Instant>,
+    commands: Sender<ChokerCommand>,
+}
+
+struct ChokerState {
+    peers: Vec<Weak<PeerSlot>>,
+    optimistic: Option<Peer>,
+    rounds: u32,
+    //Counters at the previous rechoke, rates are computed from the difference
+    previous: HashMap<Peer, (u64, u64)>,
+}
+
+//Decides which peers we upload to, shared by every connection of a torrent
+pub struct Choker {
+    pub slots: AtomicUsize,
+    state: Mutex<ChokerState>,
+}
+
+impl PeerSlot {
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+
+        if let Ok(mut last_block) = self.last_block.lock() {
+            *last_block = Instant::now();
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn is_snubbing(&self) -> bool {
+        self.last_block.lock().map(|last_block| last_block.elapsed() > SNUB_TIMEOUT).unwrap_or(false)
+    }
+
+    fn send(&self, command: ChokerCommand) {
+        self.unchoked.store(command == ChokerCommand::Unchoke, Ordering::Relaxed);
+        let _ = self.commands.try_send(command);
+    }
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots: AtomicUsize::new(slots.max(1)),
+            state: Mutex::new(ChokerState {
+                peers: Vec::new(),
+                optimistic: None,
+                rounds: 0,
+                previous: HashMap::new(),
+            }),
+        }
+    }
+
+    //Takes effect on the next rechoke, peers already unchoked keep their slot until then
+    pub fn set_slots(&self, slots: usize) {
+        self.slots.store(slots.max(1), Ordering::Relaxed);
+    }
+
+    //The slot is forgotten once the connection drops it
+    pub fn register(&self, peer: Peer) -> (Arc<PeerSlot>, Receiver<ChokerCommand>) {
+        let (commands, receiver) = async_channel::unbounded();
+        let slot = Arc::new(PeerSlot {
+            peer,
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            interested: AtomicBool::new(false),
+            unchoked: AtomicBool::new(false),
+
+            last_block: Mutex::new(Instant::now()),
+            commands,
+        });
+
+        if let Ok(mut state) = self.state.lock() {
+            state.peers.push(Arc::downgrade(&slot));
+        }
+
+        (slot, receiver)
+    }
+
+    //A newly interested peer does not wait for the next rechoke while a slot is free
+    pub fn on_interested(&self, slot: &PeerSlot) -> SyncResult<bool> {
+        let state = self.state.lock().map_err(|_| Error::Poisoned("Choker"))?;
+        slot.interested.store(true, Ordering::Relaxed);
+
+        let unchoked = state.peers.iter()
+            .filter_map(|peer| peer.upgrade())
+            .filter(|peer| peer.unchoked.load(Ordering::Relaxed))
+            .count();
+
+        if unchoked >= self.slots.load(Ordering::Relaxed) || slot.unchoked.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+
+        slot.unchoked.store(true, Ordering::Relaxed);
+
+        Ok(true)
+    }
+
+    //Unchokes the fastest interested peers plus an optimistic one, by the rate they give us or the rate we give them when seeding
+    pub fn rechoke(&self, seeding: bool) -> SyncResult<()> {
+        let mut state = self.state.lock().map_err(|_| Error::Poisoned("Choker"))?;
+        state.peers.retain(|peer| peer.strong_count() > 0);
+        let peers = state.peers.iter().filter_map(|peer| peer.upgrade()).collect::<Vec<_>>();
+
+        let mut rates = HashMap::new();
+        for peer in peers.iter() {
+            let counters = (peer.downloaded.load(Ordering::Relaxed), peer.uploaded.load(Ordering::Relaxed));
+            let (downloaded, uploaded) = state.previous.get(&peer.peer).copied().unwrap_or((0, 0));
+            let rate = match seeding {
+                true => counters.1.saturating_sub(uploaded),
+                false => counters.0.saturating_sub(downloaded),
+            };
+
+            rates.insert(peer.peer.clone(), rate);
+        }
+        state.previous = peers.iter()
+            .map(|peer| (peer.peer.clone(), (peer.downloaded.load(Ordering::Relaxed), peer.uploaded.load(Ordering::Relaxed))))
+            .collect();
+
+        //Snubbing peers can only get the optimistic slot
+        let mut candidates = peers.iter()
+            .filter(|peer| peer.interested.load(Ordering::Relaxed))
+            .filter(|peer| seeding || !peer.is_snubbing())
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|peer| std::cmp::Reverse(rates[&peer.peer]));
+
+        let mut unchoked = candidates.iter()
+            .take(self.slots.load(Ordering::Relaxed) - 1)
+            .map(|peer| peer.peer.clone())
+            .collect::<Vec<_>>();
+
+        //The optimistic peer is replaced when it left, lost interest or earned a regular slot
+        let optimistic_kept = state.optimistic.as_ref().is_some_and(|optimistic| {
+            !unchoked.contains(optimistic) && peers.iter().any(|peer| &peer.peer == optimistic && peer.interested.load(Ordering::Relaxed))
+        });
+        if state.rounds.is_multiple_of(OPTIMISTIC_ROUNDS) || !optimistic_kept {
+            let choked = peers.iter()
+                .filter(|peer| peer.interested.load(Ordering::Relaxed) && !unchoked.contains(&peer.peer))
+                .collect::<Vec<_>>();
+
+            state.optimistic = choked.choose(&mut rand::thread_rng()).map(|peer| peer.peer.clone());
+        }
+        state.rounds += 1;
+
+        if let Some(optimistic) = state.optimistic.clone() {
+            unchoked.push(optimistic);
+        }
+
+        for peer in peers.iter() {
+            let unchoke = unchoked.contains(&peer.peer);
+            if unchoke != peer.unchoked.load(Ordering::Relaxed) {
+                peer.send(if unchoke { ChokerCommand::Unchoke } else { ChokerCommand::Choke });
+            }
+        }
+
+        Ok(())
+    }
+
+    //Rechokes until the task is aborted, the picker tells whether we are seeding
+    pub async fn run(self: Arc<Self>, picker: Arc<PiecePicker>, settings: Arc<SharedSettings>) {
+        let mut interval = time::interval(RECHOKE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            self.set_slots(settings.get().upload_slots);
+
+            //A poisoned lock leaves the peers as they are until the task is aborted
+            let seeding = picker.is_complete().unwrap_or(false);
+            let _ = self.rechoke(seeding);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use super::*;
+
+    fn peer(last: u8) -> Peer {
+        Peer::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), 6881)
+    }
+
+    //Registers interested peers with what they gave us and what we gave them so far
+    fn register(choker: &Choker, transfers: &[(u64, u64)]) -> Vec<(Arc<PeerSlot>, Receiver<ChokerCommand>)> {
+        transfers.iter().enumerate().map(|(index, (downloaded, uploaded))| {
+            let (slot, commands) = choker.register(peer(index as u8));
+            slot.interested.store(true, Ordering::Relaxed);
+            slot.add_downloaded(*downloaded);
+            slot.add_uploaded(*uploaded);
+
+            (slot, commands)
+        }).collect()
+    }
+
+    fn commands(receiver: &Receiver<ChokerCommand>) -> Vec<ChokerCommand> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    fn optimistic(choker: &Choker) -> Option<Peer> {
+        choker.state.lock().unwrap().optimistic.clone()
+    }
+
+    #[test]
+    fn regular_slots_go_to_the_fastest_peers() {
+        let choker = Choker::new(3);
+        let peers = register(&choker, &[(100, 0), (500, 0), (300, 0), (400, 0), (0, 0)]);
+        peers[4].0.interested.store(false, Ordering::Relaxed);
+
+        choker.rechoke(false).unwrap();
+
+        //Two regular slots, the third one is optimistic
+        assert_eq!(commands(&peers[1].1), vec![ChokerCommand::Unchoke]);
+        assert_eq!(commands(&peers[3].1), vec![ChokerCommand::Unchoke]);
+        let optimistic = optimistic(&choker).unwrap();
+        assert!(optimistic == peer(0) || optimistic == peer(2));
+        for (slot, receiver) in [&peers[0], &peers[2]] {
+            let expected = if slot.peer == optimistic { vec![ChokerCommand::Unchoke] } else { Vec::new() };
+            assert_eq!(commands(receiver), expected);
+        }
+        //Peers that are not interested are never unchoked
+        assert!(commands(&peers[4].1).is_empty());
+
+        //Rates are measured since the previous rechoke, the slowest peer is now the fastest
+        peers[0].0.add_downloaded(1000);
+        choker.rechoke(false).unwrap();
+        assert_eq!(peers.iter().filter(|(slot, _)| slot.unchoked.load(Ordering::Relaxed)).count(), 3);
+        assert!(peers[0].0.unchoked.load(Ordering::Relaxed));
+        assert!(!peers[4].0.unchoked.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates() {
+        let choker = Choker::new(1);
+        let peers = register(&choker, &[(0, 0); 10]);
+
+        let mut current = None;
+        let mut rotations = 0;
+        for round in 0..10 * OPTIMISTIC_ROUNDS {
+            choker.rechoke(false).unwrap();
+            let optimistic = optimistic(&choker);
+            assert!(optimistic.is_some());
+
+            if optimistic != current {
+                //Only ever on a rotation round while the optimistic peer stays interested
+                assert!(round.is_multiple_of(OPTIMISTIC_ROUNDS));
+                rotations += 1;
+            }
+            current = optimistic;
+
+            //With a single slot the optimistic peer is the only one unchoked
+            let unchoked = peers.iter().filter(|(slot, _)| slot.unchoked.load(Ordering::Relaxed)).collect::<Vec<_>>();
+            assert_eq!(unchoked.len(), 1);
+            assert_eq!(Some(&unchoked[0].0.peer), current.as_ref());
+        }
+        assert!(rotations > 1);
+
+        //A peer losing interest is replaced right away
+        let (slot, receiver) = peers.iter().find(|(slot, _)| Some(&slot.peer) == current.as_ref()).unwrap();
+        commands(receiver);
+        slot.interested.store(false, Ordering::Relaxed);
+        choker.rechoke(false).unwrap();
+        assert_ne!(optimistic(&choker), current);
+        assert_eq!(commands(receiver), vec![ChokerCommand::Choke]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn snubbing_peers_only_get_the_optimistic_slot() {
+        let choker = Choker::new(2);
+        let peers = register(&choker, &[(1000, 0), (10, 0)]);
+
+        time::advance(SNUB_TIMEOUT + Duration::from_secs(1)).await;
+        peers[1].0.add_downloaded(10);
+        assert!(peers[0].0.is_snubbing());
+        assert!(!peers[1].0.is_snubbing());
+
+        //The faster peer sent nothing for too long, the regular slot goes to the other one
+        choker.rechoke(false).unwrap();
+        assert_eq!(commands(&peers[1].1), vec![ChokerCommand::Unchoke]);
+        assert_eq!(optimistic(&choker), Some(peer(0)));
+
+        //Snubbing does not matter when seeding, we only upload
+        let choker = Choker::new(2);
+        let peers = register(&choker, &[(0, 1000), (0, 10)]);
+        time::advance(SNUB_TIMEOUT + Duration::from_secs(1)).await;
+        choker.rechoke(true).unwrap();
+        assert_eq!(commands(&peers[0].1), vec![ChokerCommand::Unchoke]);
+        assert_eq!(optimistic(&choker), Some(peer(1)));
+    }
+
+    #[test]
+    fn seeding_ranks_by_upload_rate() {
+        let choker = Choker::new(2);
+        let peers = register(&choker, &[(1000, 10), (10, 1000), (500, 500)]);
+
+        choker.rechoke(true).unwrap();
+        assert_eq!(commands(&peers[1].1), vec![ChokerCommand::Unchoke]);
+        assert_ne!(optimistic(&choker), Some(peer(1)));
+
+        //Downloading again, the rate they give us counts
+        let choker = Choker::new(2);
+        let peers = register(&choker, &[(1000, 10), (10, 1000), (500, 500)]);
+        choker.rechoke(false).unwrap();
+        assert_eq!(commands(&peers[0].1), vec![ChokerCommand::Unchoke]);
+        assert_ne!(optimistic(&choker), Some(peer(0)));
+    }
+
+    #[test]
+    fn dropped_peers_are_forgotten() {
+        let choker = Choker::new(2);
+        let mut peers = register(&choker, &[(10, 0), (20, 0)]);
+
+        choker.rechoke(false).unwrap();
+        peers.remove(1);
+        choker.rechoke(false).unwrap();
+
+        assert_eq!(choker.state.lock().unwrap().peers.len(), 1);
+        assert!(peers[0].0.unchoked.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use crate::error::Error;
+use crate::settings::Settings;
+use crate::shared::{SizedBytes, SyncResult};
+use crate::types::peer::Peer;
+
+//Delay before dialing a peer again after its first failure, doubled on every following one
+const RETRY_BASE: Duration = Duration::from_secs(30);
+//Peers failing that many times in a row are forgotten until a tracker hands them out again
+const MAX_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateState {
+    Idle,
+    Dialing,
+    Connected,
+}
+
+struct Candidate {
+    state: CandidateState,
+    failures: u32,
+    retry_at: Instant,
+}
+
+struct ConnectionState {
+    //Peers we may dial, from the trackers and the DHT
+    candidates: HashMap<Peer, Candidate>,
+    //Established connections, by address and by peer id
+    peers: HashSet<Peer>,
+    peer_ids: HashSet<SizedBytes>,
+}
+
+//Decides which peers a torrent dials and which connections it keeps, shared by the engine and every connection of the torrent
+pub struct ConnectionManager {
+    state: Mutex<ConnectionState>,
+    //Woken when a connection attempt ends or a connection drops, a slot may be free
+    changed: Notify,
+}
+
+impl Candidate {
+    fn new() -> Self {
+        Self {
+            state: CandidateState::Idle,
+            failures: 0,
+            retry_at: Instant::now(),
+        }
+    }
+
+    fn retry_later(&mut self) {
+        self.state = CandidateState::Idle;
+        self.retry_at = Instant::now() + RETRY_BASE * 2u32.pow(self.failures.saturating_sub(1));
+    }
+}
+
+impl ConnectionState {
+    fn dialing(&self) -> usize {
+        self.candidates.values().filter(|candidate| candidate.state == CandidateState::Dialing).count()
+    }
+
+    fn fail(&mut self, peer: &Peer) {
+        let forget = match self.candidates.get_mut(peer) {
+            Some(candidate) => {
+                candidate.failures += 1;
+                candidate.retry_later();
+                candidate.failures >= MAX_FAILURES
+            },
+            None => false,
+        };
+
+        if forget {
+            self.candidates.remove(peer);
+        }
+    }
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionManager {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(ConnectionState {
+                candidates: HashMap::new(),
+                peers: HashSet::new(),
+                peer_ids: HashSet::new(),
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    //Trackers hand out the same peers on every announce, known ones keep their backoff
+    pub fn add_candidate(&self, peer: Peer) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        if !state.peers.contains(&peer) {
+            state.candidates.entry(peer).or_insert_with(Candidate::new);
+        }
+
+        Ok(())
+    }
+
+    //Picks the next peer to dial and counts it as half-open, None when a limit is reached or no candidate is due
+    pub fn next_dial(&self, settings: &Settings) -> SyncResult<Option<Peer>> {
+        let mut state = self.lock()?;
+
+        let dialing = state.dialing();
+        if dialing >= settings.max_half_open || state.peers.len() + dialing >= settings.max_connections {
+            return Ok(None);
+        }
+
+        //Peers that never failed go first
+        let now = Instant::now();
+        let peer = state.candidates.iter()
+            .filter(|(_, candidate)| candidate.state == CandidateState::Idle && candidate.retry_at <= now)
+            .min_by_key(|(_, candidate)| (candidate.failures, candidate.retry_at))
+            .map(|(peer, _)| peer.clone());
+
+        if let Some(candidate) = peer.as_ref().and_then(|peer| state.candidates.get_mut(peer)) {
+            candidate.state = CandidateState::Dialing;
+        }
+
+        Ok(peer)
+    }
+
+    //Whether the trackers should be asked for more peers, every candidate is connected, dialing or backing off
+    pub fn wants_peers(&self, settings: &Settings) -> SyncResult<bool> {
+        let state = self.lock()?;
+
+        let now = Instant::now();
+        let due = state.candidates.values().any(|candidate| candidate.state == CandidateState::Idle && candidate.retry_at <= now);
+
+        Ok(!due && state.peers.len() + state.dialing() < settings.max_connections)
+    }
+
+    //Refused dials, like the ones reaching ourselves, are never retried
+    pub fn dial_failed(&self, peer: &Peer, error: &Error) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        match error {
+            Error::Refused(_) => { state.candidates.remove(peer); },
+            _ => state.fail(peer),
+        }
+        drop(state);
+
+        self.changed.notify_one();
+
+        Ok(())
+    }
+
+    //Checks an established connection, inbound or dialed, against the limit and the connections we already have
+    pub fn register(&self, peer: &Peer, peer_id: SizedBytes, settings: &Settings) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        let duplicate = state.peers.contains(peer) || state.peer_ids.contains(&peer_id);
+        let full = state.peers.len() >= settings.max_connections;
+        //Inbound peers connect from another port than the one they listen on, they have no candidate
+        let dialed = state.candidates.get(peer).is_some_and(|candidate| candidate.state == CandidateState::Dialing);
+
+        let result = match (duplicate, full) {
+            (true, _) => Err(Error::Refused("Already connected to this peer")),
+            (false, true) => Err(Error::Refused("Too many connections")),
+            (false, false) => Ok(()),
+        };
+
+        if dialed && duplicate {
+            //The peer is reachable through the connection we have already
+            state.candidates.remove(peer);
+        } else if let Some(candidate) = state.candidates.get_mut(peer).filter(|_| dialed) {
+            match result {
+                Ok(()) => {
+                    candidate.state = CandidateState::Connected;
+                    candidate.failures = 0;
+                },
+                Err(_) => candidate.retry_later(),
+            }
+        }
+
+        if result.is_ok() {
+            state.peers.insert(peer.clone());
+            state.peer_ids.insert(peer_id);
+        }
+        drop(state);
+
+        self.changed.notify_one();
+
+        result
+    }
+
+    //A connection that ended with an error counts as a failed dial, a clean one may be dialed again after the base delay
+    pub fn disconnected(&self, peer: &Peer, peer_id: SizedBytes, failed: bool) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        state.peers.remove(peer);
+        state.peer_ids.remove(&peer_id);
+
+        let connected = state.candidates.get(peer).is_some_and(|candidate| candidate.state == CandidateState::Connected);
+        if connected && failed {
+            state.fail(peer);
+        } else if let Some(candidate) = state.candidates.get_mut(peer).filter(|_| connected) {
+            candidate.retry_later();
+        }
+        drop(state);
+
+        self.changed.notify_one();
+
+        Ok(())
+    }
+
+    //Forgets every connection and attempt, for when the connection tasks were aborted without reporting
+    pub fn reset(&self) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        state.peers.clear();
+        state.peer_ids.clear();
+        for candidate in state.candidates.values_mut() {
+            candidate.state = CandidateState::Idle;
+        }
+
+        Ok(())
+    }
+
+    pub fn connections(&self) -> SyncResult<usize> {
+        Ok(self.lock()?.peers.len())
+    }
+
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    fn lock(&self) -> SyncResult<MutexGuard<'_, ConnectionState>> {
+        self.state.lock().map_err(|_| Error::Poisoned("Connection manager"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::time;
+    use super::*;
+
+    fn peer(last: u8) -> Peer {
+        Peer::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), 6881)
+    }
+
+    fn peer_id(last: u8) -> SizedBytes {
+        [last; 20]
+    }
+
+    fn settings(max_connections: usize, max_half_open: usize) -> Settings {
+        Settings {
+            max_connections,
+            max_half_open,
+            ..Settings::default()
+        }
+    }
+
+    fn add(manager: &ConnectionManager, count: u8) {
+        for last in 0..count {
+            manager.add_candidate(peer(last)).unwrap();
+        }
+    }
+
+    fn failures(manager: &ConnectionManager, peer: &Peer) -> Option<u32> {
+        manager.lock().unwrap().candidates.get(peer).map(|candidate| candidate.failures)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_half_open_and_connections() {
+        let manager = ConnectionManager::new();
+        let settings = settings(3, 2);
+        add(&manager, 5);
+
+        let first = manager.next_dial(&settings).unwrap().unwrap();
+        let second = manager.next_dial(&settings).unwrap().unwrap();
+        assert_ne!(first, second);
+        //Two attempts are already in flight
+        assert_eq!(manager.next_dial(&settings).unwrap(), None);
+
+        manager.register(&first, peer_id(1), &settings).unwrap();
+        manager.register(&second, peer_id(2), &settings).unwrap();
+        assert_eq!(manager.connections().unwrap(), 2);
+
+        //One more connection fits, counting the one being dialed
+        let third = manager.next_dial(&settings).unwrap().unwrap();
+        assert_eq!(manager.next_dial(&settings).unwrap(), None);
+        assert!(!manager.wants_peers(&settings).unwrap());
+
+        manager.register(&third, peer_id(3), &settings).unwrap();
+        assert_eq!(manager.next_dial(&settings).unwrap(), None);
+        //Inbound connections are refused too once the limit is reached
+        assert!(matches!(manager.register(&peer(100), peer_id(100), &settings), Err(Error::Refused(_))));
+
+        //A connection dropping frees a slot
+        manager.disconnected(&first, peer_id(1), false).unwrap();
+        assert!(manager.next_dial(&settings).unwrap().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_dials_back_off() {
+        let manager = ConnectionManager::new();
+        let settings = settings(10, 10);
+        let candidate = peer(1);
+        manager.add_candidate(candidate.clone()).unwrap();
+
+        for failure in 1..MAX_FAILURES {
+            assert_eq!(manager.next_dial(&settings).unwrap(), Some(candidate.clone()));
+            manager.dial_failed(&candidate, &Error::PeerTimeout("Peer connection")).unwrap();
+            assert_eq!(failures(&manager, &candidate), Some(failure));
+
+            //30 seconds after the first failure, doubled on every following one
+            let delay = RETRY_BASE * 2u32.pow(failure - 1);
+            time::advance(delay - Duration::from_secs(1)).await;
+            assert_eq!(manager.next_dial(&settings).unwrap(), None);
+            //Asking the trackers is worth it while the only candidate backs off
+            assert!(manager.wants_peers(&settings).unwrap());
+
+            time::advance(Duration::from_secs(1)).await;
+        }
+
+        //Forgotten after the fifth failure in a row
+        assert_eq!(manager.next_dial(&settings).unwrap(), Some(candidate.clone()));
+        manager.dial_failed(&candidate, &Error::PeerTimeout("Peer connection")).unwrap();
+        assert_eq!(failures(&manager, &candidate), None);
+
+        //A tracker handing it out again starts it over
+        manager.add_candidate(candidate.clone()).unwrap();
+        assert_eq!(manager.next_dial(&settings).unwrap(), Some(candidate));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_that_never_failed_go_first() {
+        let manager = ConnectionManager::new();
+        let settings = settings(10, 10);
+        manager.add_candidate(peer(1)).unwrap();
+
+        manager.next_dial(&settings).unwrap();
+        manager.dial_failed(&peer(1), &Error::PeerTimeout("Peer connection")).unwrap();
+        time::advance(RETRY_BASE).await;
+
+        manager.add_candidate(peer(2)).unwrap();
+        assert_eq!(manager.next_dial(&settings).unwrap(), Some(peer(2)));
+        assert_eq!(manager.next_dial(&settings).unwrap(), Some(peer(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_successful_connection_resets_the_failures() {
+        let manager = ConnectionManager::new();
+        let settings = settings(10, 10);
+        let candidate = peer(1);
+        manager.add_candidate(candidate.clone()).unwrap();
+
+        manager.next_dial(&settings).unwrap();
+        manager.dial_failed(&candidate, &Error::PeerTimeout("Peer connection")).unwrap();
+        time::advance(RETRY_BASE).await;
+
+        manager.next_dial(&settings).unwrap();
+        manager.register(&candidate, peer_id(1), &settings).unwrap();
+        assert_eq!(failures(&manager, &candidate), Some(0));
+
+        //A connection ending cleanly waits for the base delay, one ending with an error counts as a failure
+        manager.disconnected(&candidate, peer_id(1), false).unwrap();
+        assert_eq!(failures(&manager, &candidate), Some(0));
+        assert_eq!(manager.next_dial(&settings).unwrap(), None);
+        time::advance(RETRY_BASE).await;
+
+        manager.next_dial(&settings).unwrap();
+        manager.register(&candidate, peer_id(1), &settings).unwrap();
+        manager.disconnected(&candidate, peer_id(1), true).unwrap();
+        assert_eq!(failures(&manager, &candidate), Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejects_duplicates_and_ourselves() {
+        let manager = ConnectionManager::new();
+        let settings = settings(10, 10);
+        add(&manager, 3);
+
+        let first = manager.next_dial(&settings).unwrap().unwrap();
+        manager.register(&first, peer_id(1), &settings).unwrap();
+
+        //Known peers are not dialed again while connected
+        manager.add_candidate(first.clone()).unwrap();
+        let second = manager.next_dial(&settings).unwrap().unwrap();
+        assert_ne!(second, first);
+
+        //The same peer id behind another address is a duplicate, the candidate is dropped since we reach the peer already
+        assert!(matches!(manager.register(&second, peer_id(1), &settings), Err(Error::Refused(_))));
+        assert_eq!(failures(&manager, &second), None);
+        //An inbound duplicate of the same address too
+        assert!(matches!(manager.register(&first, peer_id(9), &settings), Err(Error::Refused(_))));
+        assert_eq!(manager.connections().unwrap(), 1);
+
+        //Dialing ourselves is refused for good
+        let third = manager.next_dial(&settings).unwrap().unwrap();
+        manager.dial_failed(&third, &Error::Refused("Connected to ourselves")).unwrap();
+        assert_eq!(failures(&manager, &third), None);
+        assert_eq!(manager.next_dial(&settings).unwrap(), None);
+    }
+}
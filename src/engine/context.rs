@@ -1,37 +1,260 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use async_channel::Sender;
+use crate::connection::bandwidth::{SessionBandwidth, TorrentBandwidth};
+use crate::connection::extension::ExtensionRegistry;
+use crate::connection::metadata::MetadataProvider;
+use crate::engine::choker::Choker;
+use crate::engine::connections::ConnectionManager;
+use crate::engine::events::{Event, EventBus};
+use crate::engine::picker::PiecePicker;
+use crate::error::Error;
+use crate::settings::SharedSettings;
 use crate::shared::{SizedBytes, SyncResult};
+use crate::storage::backend::Storage;
+use crate::storage::cache::PieceCache;
+use crate::storage::disk::FileStorage;
+use crate::storage::layout::StorageLayout;
+use crate::storage::recheck::{RecheckProgress, RecheckReport, Rechecker};
+use crate::storage::resume::ResumeData;
 use crate::types::bencode::MetaInfoFile;
+use crate::types::bitfield::BitField;
+use crate::types::stats::TransferStats;
 use crate::utils::data::manipulator;
 
 pub struct EngineContext {
     pub name: String,
     pub announce: String,
+    //Tracker tiers from the announce-list, or the single announce url when there is none
+    pub announce_list: Vec<Vec<String>>,
 
     pub info_hash: String,
+    //Private torrents only get peers from their trackers, never from the DHT
+    pub private: bool,
 
     pub piece_length: u32,
-    pub length: u32,
+    pub length: u64,
     pub pieces: Vec<SizedBytes>,
 
-    pub destination: tokio::fs::File,
+    pub storage: Arc<dyn Storage>,
+    pub stats: Arc<TransferStats>,
+    //Pieces we have verified and written, advertised to peers
+    pub have: Arc<RwLock<BitField>>,
+    pub picker: Arc<PiecePicker>,
+    pub choker: Arc<Choker>,
+    pub connections: Arc<ConnectionManager>,
+    pub bandwidth: Arc<TorrentBandwidth>,
+    pub events: EventBus,
+    //Replaced by the ones of the session the torrent is added to
+    pub settings: Arc<SharedSettings>,
+    pub cache: Arc<PieceCache>,
+
+    //Resume data is only kept for file storages
+    pub resume_path: Option<PathBuf>,
+    //Whether the pieces we start with come from resume data, without it nothing on disk is trusted
+    pub resumed: bool,
+    //Tracker ids from the resume data, refreshed when the tracker session stops
+    pub tracker_ids: BTreeMap<String, String>,
+
+    //Identity of the session, sent in handshakes and announces
+    pub peer_id: SizedBytes,
+    pub listen_port: u16,
+    pub extensions: Arc<ExtensionRegistry>,
+}
+
+//What a single peer connection needs to know about its torrent
+#[derive(Clone)]
+pub struct PeerContext {
+    pub info_hash: String,
+    pub piece_count: u32,
+
+    pub storage: Arc<dyn Storage>,
+    pub stats: Arc<TransferStats>,
+    pub have: Arc<RwLock<BitField>>,
+    pub picker: Arc<PiecePicker>,
+    pub choker: Arc<Choker>,
+    pub connections: Arc<ConnectionManager>,
+    pub bandwidth: Arc<TorrentBandwidth>,
+    pub events: EventBus,
+    pub settings: Arc<SharedSettings>,
+    pub cache: Arc<PieceCache>,
+
+    pub peer_id: SizedBytes,
+    pub listen_port: u16,
+    pub extensions: Arc<ExtensionRegistry>,
 }
 
 impl EngineContext {
-    pub async fn new(meta_info: MetaInfoFile, length: u32, destination: PathBuf) -> SyncResult<Self> {
+    //Stores the torrent in files under destination, resuming from what they already hold
+    pub async fn new(meta_info: MetaInfoFile, destination: PathBuf, state_directory: Option<PathBuf>) -> SyncResult<Self> {
+        let info_hash = manipulator::hash_meta_info(&meta_info)?;
+        let resume_path = ResumeData::path(&destination, state_directory.as_deref(), &info_hash);
+
+        let layout = StorageLayout::from_meta_info(&meta_info, destination)?;
+        let works = manipulator::piece_works(&manipulator::split_piece_bytes(&meta_info)?, layout.length, meta_info.info.piece_length);
+        let piece_count = works.len() as u32;
+
+        //The resume data is checked against the files before opening them may resize them
+        let (storage, resume) = {
+            let resume_path = resume_path.clone();
+            let info_hash = info_hash.clone();
+            tokio::task::spawn_blocking(move || -> SyncResult<_> {
+                //Unreadable resume data only means checking everything again
+                let resume = ResumeData::load(&resume_path).ok().flatten();
+                let verified = resume.as_ref().map(|resume| resume.verified_pieces(&info_hash, &layout, piece_count));
+                let storage = FileStorage::create(layout)?;
+
+                //Pieces of changed files are kept only if they still match their hash
+                let have = verified.map(|(mut have, recheck)| {
+                    for index in recheck {
+                        if storage.verify_piece(&works[index as usize]).unwrap_or(false) {
+                            have.set_piece(index);
+                        }
+                    }
+                    have
+                });
+
+                Ok((storage, resume.zip(have)))
+            }).await??
+        };
+        let resumed = resume.is_some();
+        let (tracker_ids, have) = match resume {
+            Some((resume, have)) => (resume.tracker_ids, have),
+            None => (BTreeMap::new(), BitField::empty(piece_count)),
+        };
+
+        let mut context = EngineContext::build(meta_info, Arc::new(storage), have)?;
+        context.resume_path = Some(resume_path);
+        context.resumed = resumed;
+        context.tracker_ids = tracker_ids;
+
+        Ok(context)
+    }
+
+    //Stores the torrent in any storage, starting from nothing since there is no resume data for it
+    pub fn with_storage(meta_info: MetaInfoFile, storage: Arc<dyn Storage>) -> SyncResult<Self> {
+        let piece_count = manipulator::split_piece_bytes(&meta_info)?.len() as u32;
+
+        EngineContext::build(meta_info, storage, BitField::empty(piece_count))
+    }
+
+    fn build(meta_info: MetaInfoFile, storage: Arc<dyn Storage>, have: BitField) -> SyncResult<Self> {
         let name = meta_info.info.name.clone();
         let announce = meta_info.announce.clone();
+        let announce_list = match meta_info.announce_list.clone() {
+            Some(announce_list) if announce_list.iter().any(|tier| !tier.is_empty()) => announce_list,
+            //Magnet links may come without any tracker, peers are then found through the DHT
+            _ if announce.is_empty() => Vec::new(),
+            _ => vec![vec![announce.clone()]],
+        };
         let piece_length = meta_info.info.piece_length;
+        let private = meta_info.info.private == Some(1);
 
-        let destination = tokio::fs::File::open(destination).await?;
+        let length = storage.layout().length;
+        let pieces = manipulator::split_piece_bytes(&meta_info)?;
+        //Custom storages bring their own layout, it may not match the torrent
+        storage.layout().check_piece_count(pieces.len())?;
+
+        let works = manipulator::piece_works(&pieces, length, piece_length);
+        let left = works.iter().filter(|work| !have.has_piece(work.index)).map(|work| work.length as u64).sum();
+        let picker = PiecePicker::new(works, &have);
+
+        //Peers resolving a magnet link of this torrent can get its metadata from us
+        let extensions = ExtensionRegistry::new();
+        extensions.register(Arc::new(MetadataProvider::new(meta_info.encoded_info()?)))?;
+        let settings = Arc::new(SharedSettings::default());
+        let defaults = settings.get();
 
         Ok(Self {
             name,
             announce,
+            announce_list,
             info_hash: manipulator::hash_meta_info(&meta_info)?,
+            private,
             piece_length,
             length,
-            pieces: manipulator::split_piece_bytes(&meta_info)?,
-            destination,
+            pieces,
+            storage,
+            stats: Arc::new(TransferStats::new(left)),
+            have: Arc::new(RwLock::new(have)),
+            picker: Arc::new(picker),
+            choker: Arc::new(Choker::new(defaults.upload_slots)),
+            connections: Arc::new(ConnectionManager::new()),
+            bandwidth: Arc::new(TorrentBandwidth::new(Arc::new(SessionBandwidth::default()))),
+            events: EventBus::new(),
+            settings,
+            cache: Arc::new(PieceCache::new()),
+
+            resume_path: None,
+            resumed: false,
+            tracker_ids: BTreeMap::new(),
+
+            peer_id: defaults.generate_peer_id(),
+            listen_port: defaults.listen_port,
+            extensions: Arc::new(extensions),
         })
     }
+
+    pub fn peer_context(&self) -> PeerContext {
+        PeerContext {
+            info_hash: self.info_hash.clone(),
+            piece_count: self.pieces.len() as u32,
+
+            storage: self.storage.clone(),
+            stats: self.stats.clone(),
+            have: self.have.clone(),
+            picker: self.picker.clone(),
+            choker: self.choker.clone(),
+            connections: self.connections.clone(),
+            bandwidth: self.bandwidth.clone(),
+            events: self.events.clone(),
+            settings: self.settings.clone(),
+            cache: self.cache.clone(),
+
+            peer_id: self.peer_id,
+            listen_port: self.listen_port,
+            extensions: self.extensions.clone(),
+        }
+    }
+
+    //Flushes the storage, then snapshots it when it keeps resume data, every piece in have is written by then
+    pub async fn save_resume(&self) -> SyncResult<()> {
+        let have = self.have.read().map_err(|_| Error::Poisoned("Have bitfield"))?.clone();
+        let info_hash = self.info_hash.clone();
+        let storage = self.storage.clone();
+        let tracker_ids = self.tracker_ids.clone();
+        let resume_path = self.resume_path.clone();
+
+        //Flushed first so that the saved modification times come after every write
+        tokio::task::spawn_blocking(move || {
+            storage.flush()?;
+
+            match resume_path {
+                Some(resume_path) => ResumeData::capture(&info_hash, &have, storage.layout(), tracker_ids)?.save(&resume_path),
+                None => Ok(()),
+            }
+        }).await?
+    }
+
+    //Hashes what is already on disk and trusts only that, to be run before the download starts
+    pub async fn recheck(&self, progress: Option<Sender<RecheckProgress>>) -> SyncResult<RecheckReport> {
+        let works = manipulator::piece_works(&self.pieces, self.length, self.piece_length);
+        let report = Rechecker::new(self.storage.clone(), works.clone()).run(progress).await?;
+
+        let left = works.iter().filter(|work| !report.have.has_piece(work.index)).map(|work| work.length as u64).sum();
+        *self.have.write().map_err(|_| Error::Poisoned("Have bitfield"))? = report.have.clone();
+        self.picker.reset(&report.have)?;
+        self.stats.set_left(left);
+        self.cache.clear()?;
+
+        self.save_resume().await?;
+        self.events.emit(Event::RecheckFinished {
+            info_hash: self.info_hash.clone(),
+            valid: report.have.count_pieces(),
+            mismatched: report.mismatched.clone(),
+        });
+
+        Ok(report)
+    }
 }
\ No newline at end of file
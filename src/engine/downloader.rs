@@ -1,69 +1,133 @@
 use std::time::Duration;
-use async_channel::{Receiver, Sender};
+use async_channel::Sender;
 use tokio::io::AsyncWriteExt;
 use tokio::time;
 use crate::connection::client::Client;
-use crate::shared::{MAX_BACKLOG, MAX_BLOCK_SIZE, SyncResult};
+use crate::engine::context::PeerContext;
+use crate::engine::events::Event;
+use crate::error::Error;
+use crate::shared::SyncResult;
 use crate::types::peer::Peer;
 use crate::types::piece::{PieceProgress, PieceResult, PieceWork};
+use crate::utils::data::manipulator;
+
+//Waiting for work wakes up on its own from time to time, a piece release may have been missed
+const IDLE_RECHECK: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct Downloader {
     pub peer: Peer,
-    pub info_hash: String,
-    pub work_sender: Sender<PieceWork>,
+    pub context: PeerContext,
     pub result_sender: Sender<PieceResult>,
-    pub work_receiver: Receiver<PieceWork>,
 }
 
 impl Downloader {
-    pub fn new(peer: Peer, info_hash: String, work_sender: Sender<PieceWork>, result_sender: Sender<PieceResult>, work_receiver: Receiver<PieceWork>) -> Self {
+    pub fn new(peer: Peer, context: PeerContext, result_sender: Sender<PieceResult>) -> Self {
         Self {
             peer,
-            info_hash,
-            work_sender,
+            context,
             result_sender,
-            work_receiver
         }
     }
 
+    //Dials the peer, which the connection manager counts as half-open until it answers
     pub async fn start_worker(&self) -> SyncResult<()> {
-        println!("[Downloader - start_worker] Starting worker for peer {}:{}", self.peer.ip, self.peer.port);
-        let mut client = Client::connect(self.peer.clone(), self.info_hash.clone()).await?;
+        let client = match Client::connect(self.peer.clone(), &self.context).await {
+            Ok(client) => client,
+            Err(error) => {
+                self.context.connections.dial_failed(&self.peer, &error)?;
+                self.context.events.emit(Event::PeerConnectFailed {
+                    info_hash: self.context.info_hash.clone(),
+                    peer: self.peer.clone(),
+                    message: error.to_string(),
+                });
+                return Err(error);
+            }
+        };
+
+        self.start_worker_with(client).await
+    }
+
+    //Runs the worker on an already established connection, like the ones accepted by the listener
+    pub async fn start_worker_with(&self, mut client: Client) -> SyncResult<()> {
+        let settings = self.context.settings.get();
+        if let Err(error) = self.context.connections.register(&client.peer, client.peer_id, &settings) {
+            self.context.events.emit(Event::PeerConnectFailed {
+                info_hash: self.context.info_hash.clone(),
+                peer: client.peer.clone(),
+                message: error.to_string(),
+            });
+            self.context.picker.remove_peer(&client.bitfield)?;
+            client.connection.shutdown().await.map_err(Error::Network)?;
+
+            return Err(error);
+        }
+        self.context.events.emit(Event::PeerConnected { info_hash: self.context.info_hash.clone(), peer: client.peer.clone() });
 
         let result = self.start_safe_worker(&mut client).await;
+        self.context.connections.disconnected(&client.peer, client.peer_id, result.is_err())?;
+        client.close_extensions(&self.context);
+        self.context.events.emit(Event::PeerDisconnected {
+            info_hash: self.context.info_hash.clone(),
+            peer: client.peer.clone(),
+            error: result.as_ref().err().map(|error| error.to_string()),
+        });
+        self.context.picker.remove_peer(&client.bitfield)?;
+
         if result.is_err() {
-            println!("[Downloader - start_worker] Shutting down worker");
-            client.connection.shutdown().await?;
+            client.connection.shutdown().await.map_err(Error::Network)?;
         }
 
-        return result
+        result
     }
 
     pub async fn start_safe_worker(&self, client: &mut Client) -> SyncResult<()> {
-        client.send_unchoke().await?;
-        println!("[Downloader - start_safe_worker] Unchoke sent");
-        client.send_interested().await?;
-        println!("[Downloader - start_safe_worker] Interested sent");
-
-        while let Ok(piece_work) = self.work_receiver.recv().await {
-            if !client.bitfield.has_piece(piece_work.index as u32) {
-                println!("[Downloader - start_safe_worker] Skipping piece {} because we don't have it", piece_work.index);
-                self.work_sender.send(piece_work).await?;
+        //Unchoking is left to the choker, interest follows the pieces the peer has and the ones we still need
+        client.update_interest(&self.context).await?;
+
+        while !self.context.picker.is_complete()? {
+            let piece_work = match self.context.picker.pick(&client.bitfield, &client.suggested)? {
+                Some(piece_work) => piece_work,
+                None => {
+                    //Other peers may have completed everything this one has
+                    client.update_interest(&self.context).await?;
+                    self.wait_for_work(client).await?;
+                    continue;
+                }
+            };
+
+            let piece_data = match piece_work.download_piece(client, &self.context).await {
+                Ok(Some(piece_data)) => piece_data,
+                //Another peer finished it first during the endgame
+                Ok(None) => {
+                    self.context.picker.abort(piece_work.index)?;
+                    continue;
+                },
+                Err(error) => {
+                    self.context.picker.abort(piece_work.index)?;
+                    return Err(error);
+                }
+            };
+
+            if !piece_work.check_integrity(&piece_data) {
+                client.hash_failures += 1;
+                self.context.events.emit(Event::HashFailed { info_hash: self.context.info_hash.clone(), index: piece_work.index, peer: client.peer.clone() });
+                self.context.picker.abort(piece_work.index)?;
+
+                //The last bad piece is reported, the peer sent too many of them
+                if client.hash_failures >= self.context.settings.get().max_hash_failures {
+                    return Err(Error::HashMismatch(piece_work.index));
+                }
+
                 continue;
             }
 
-            println!("[Downloader - start_safe_worker] Starting downloading piece {}", piece_work.index);
-            let piece_data = piece_work.download_piece(client).await;
-            if piece_data.is_err() {
-                println!("[Downloader - start_safe_worker] Failed to download piece {}: {:?}", piece_work.index, piece_data.unwrap_err());
-                self.work_sender.send(piece_work).await?;
+            //Two peers may finish the same piece at once in endgame, only the first one counts
+            if !self.context.picker.complete(piece_work.index)? {
                 continue;
             }
 
-            let piece_data = piece_data.unwrap();
-
-            client.send_have(piece_work.index as u32).await?;
+            client.send_have(piece_work.index).await?;
             let result = PieceResult {
                 index: piece_work.index,
                 data: piece_data,
@@ -72,49 +136,105 @@ impl Downloader {
             self.result_sender.send(result).await?;
         }
 
+        //Nothing is left to download, keep serving the peer from there
+        client.seed(&self.context).await
+    }
+
+    //The peer has nothing we need right now, serve it until it gets a new piece or another peer gives one up
+    async fn wait_for_work(&self, client: &mut Client) -> SyncResult<()> {
+        tokio::select! {
+            readable = client.connection.readable() => {
+                readable.map_err(Error::Network)?;
+                let message = client.read_message().await?;
+                client.handle_message(&message, &self.context).await?;
+            },
+            Ok(command) = client.choker_commands.recv() => client.apply_choker_command(command).await?,
+            _ = self.context.picker.released() => {},
+            _ = time::sleep(IDLE_RECHECK) => {},
+        }
+
         Ok(())
     }
 }
 
 impl PieceWork {
-    pub async fn download_piece(&self, client: &mut Client) -> SyncResult<Vec<u8>> {
-        let mut progress = PieceProgress::new(self.index);
+    pub fn check_integrity(&self, data: &[u8]) -> bool {
+        data.len() == self.length as usize && manipulator::hash_piece(data) == self.hash
+    }
+
+    //Returns None when another peer completed the piece first
+    pub async fn download_piece(&self, client: &mut Client, context: &PeerContext) -> SyncResult<Option<Vec<u8>>> {
+        let settings = context.settings.get();
+        let mut progress = PieceProgress::new(self.index, self.length, settings.block_size);
 
-        //30 seconds timeout to download a piece
-        let timeout_duration = Duration::from_secs(30);
-        let timeout = time::timeout(timeout_duration, self.download_piece_safe(client, &mut progress)).await;
+        let timeout = time::timeout(settings.piece_timeout(), self.download_piece_safe(client, context, &mut progress)).await;
 
-        if timeout.is_err() {
-            return Err("Timeout while downloading piece".into());
+        //Requests still pending in endgame are not worth counting anymore
+        if !matches!(timeout, Ok(Ok(true))) {
+            context.picker.release_blocks(self.index, &progress.pending)?;
         }
 
-        Ok(progress.data)
+        match timeout {
+            Err(_) => Err(Error::PeerTimeout("Piece download")),
+            Ok(Err(error)) => Err(error),
+            Ok(Ok(false)) => Ok(None),
+            Ok(Ok(true)) => Ok(Some(progress.data)),
+        }
     }
 
-    pub async fn download_piece_safe(&self, client: &mut Client, progress: &mut PieceProgress) -> SyncResult<()> {
+    pub async fn download_piece_safe(&self, client: &mut Client, context: &PeerContext, progress: &mut PieceProgress) -> SyncResult<bool> {
+        let queue_depth = context.settings.get().request_queue_depth;
+
         while progress.downloaded < self.length {
-            if !client.choked {
-                println!("[PieceWork - download_piece_safe] Client is not choked, requesting block for piece {}", self.index);
-                while progress.backlog < MAX_BACKLOG && progress.requested < self.length {
-                    let mut block_size = MAX_BLOCK_SIZE;
+            if context.picker.is_done(self.index)? {
+                //Blocks still on their way would only waste bandwidth
+                for (begin, length) in progress.pending.drain(..) {
+                    client.send_cancel(self.index, begin, length).await?;
+                }
+
+                return Ok(false);
+            }
+
+            //In endgame, blocks another peer delivered first are not needed from this one anymore
+            for (begin, length) in context.picker.sync_blocks(progress)? {
+                client.send_cancel(self.index, begin, length).await?;
+            }
+
+            if progress.downloaded >= self.length {
+                break;
+            }
 
-                    if self.length - progress.requested < block_size {
-                        block_size = self.length - progress.requested;
+            //Allowed fast pieces can be requested while choked
+            if !client.choked || client.allowed_fast.contains(&self.index) {
+                while progress.backlog < queue_depth {
+                    let (begin, block_size) = match progress.next_block() {
+                        Some(block) => block,
+                        None => break,
+                    };
+
+                    if !context.picker.request_block(self.index, begin)? {
+                        continue;
                     }
 
-                    client.send_request(self.index, progress.requested, block_size).await?;
-                    println!("[PieceWork - download_piece_safe] Request sent for piece {} at offset {} with size {}", self.index, progress.requested, block_size);
+                    client.send_request(self.index, begin, block_size).await?;
 
+                    progress.pending.push((begin, block_size));
                     progress.backlog += 1;
-                    progress.requested += block_size;
                 }
             }
 
-            println!("[PieceWork - download_piece_safe] Waiting for block for piece {}", self.index);
-            progress.parse_message(client).await?;
-            println!("[PieceWork - download_piece_safe] Message parsed for piece {}", self.index);
+            client.apply_choker_commands().await?;
+
+            tokio::select! {
+                readable = client.connection.readable() => {
+                    readable.map_err(Error::Network)?;
+                    progress.parse_message(client, context).await?;
+                },
+                //Another peer delivered a block we may have requested too, it is cancelled on the next turn
+                _ = context.picker.landed() => {},
+            }
         }
 
-        Ok(())
+        Ok(true)
     }
 }
\ No newline at end of file
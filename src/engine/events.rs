@@ -0,0 +1,88 @@
+use tokio::sync::broadcast;
+use crate::types::peer::Peer;
+
+//Events a subscriber may fall behind by before it starts missing the oldest ones
+const EVENT_CAPACITY: usize = 1024;
+
+//Everything a torrent or the session reports, torrents are referred to by their hex info hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    TorrentAdded { info_hash: String },
+    TorrentPaused { info_hash: String },
+    TorrentResumed { info_hash: String },
+    TorrentRemoved { info_hash: String },
+    //Every piece is downloaded and written, the torrent seeds from there
+    TorrentFinished { info_hash: String },
+    //The torrent stopped on its own
+    TorrentError { info_hash: String, message: String },
+
+    PieceFinished { info_hash: String, index: u32 },
+    HashFailed { info_hash: String, index: u32, peer: Peer },
+    RecheckFinished { info_hash: String, valid: u32, mismatched: Vec<u32> },
+
+    PeerConnected { info_hash: String, peer: Peer },
+    //The error is the reason the connection was dropped, if it was not closed cleanly
+    PeerDisconnected { info_hash: String, peer: Peer, error: Option<String> },
+    PeerConnectFailed { info_hash: String, peer: Peer, message: String },
+
+    TrackerReply { info_hash: String, url: String, peers: usize },
+    TrackerWarning { info_hash: String, message: String },
+    TrackerError { info_hash: String, url: String, message: String },
+
+    DhtReply { info_hash: String, peers: usize },
+    //Errors of the node itself have no torrent
+    DhtError { info_hash: Option<String>, message: String },
+
+    StorageError { info_hash: String, message: String },
+
+    //Inbound connections failing to be accepted, like when we run out of file descriptors
+    ListenerError { message: String },
+}
+
+//Subscribable channel of events, the ones of a torrent are forwarded to the session it belongs to
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    parent: Option<broadcast::Sender<Event>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+
+        Self {
+            sender,
+            parent: None,
+        }
+    }
+
+    //Bus of a torrent whose events reach the subscribers of this one too
+    pub fn torrent(&self) -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+
+        Self {
+            sender,
+            parent: Some(self.sender.clone()),
+        }
+    }
+
+    //Only receives the events emitted from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    //Nobody listening is fine, the event is simply dropped
+    pub fn emit(&self, event: Event) {
+        if let Some(parent) = &self.parent {
+            let _ = parent.send(event.clone());
+        }
+
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
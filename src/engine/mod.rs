@@ -1,91 +1,336 @@
-use std::io::SeekFrom;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use std::sync::Arc;
+use std::time::Duration;
+use async_channel::{Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use crate::connection::client::Client;
+use crate::connection::extension::Extension;
+use crate::connection::listener::InboundTarget;
+use crate::dht::node::DhtNode;
 use crate::engine::context::EngineContext;
 use crate::engine::downloader::Downloader;
-use crate::protocol::tracker;
-use crate::shared::SyncResult;
-use crate::types::piece::{PieceResult, PieceWork};
-use crate::utils::data::calculator;
+use crate::engine::events::{Event, EventBus};
+use crate::error::Error;
+use crate::protocol::announce::AnnounceRequest;
+use crate::protocol::manager::TrackerManager;
+use crate::protocol::session::{TrackerCommand, TrackerSession};
+use crate::shared::{SizedBytes, SyncResult};
+use crate::types::peer::Peer;
+use crate::types::piece::PieceResult;
 
+pub mod choker;
+pub mod connections;
 pub mod context;
-pub mod manager;
 pub mod downloader;
+pub mod events;
+pub mod picker;
+pub mod session;
+pub mod uploader;
+
+//How often the torrent is looked up and announced on the DHT
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+//Resume data is saved at most this often while downloading, a crash loses at most that much progress
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+//How often peers whose backoff ended are dialed again, and trackers asked for more when we run out
+const CONNECT_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct Engine {
     pub context: EngineContext,
-    pub downloaders: Vec<Downloader>
+    //Taken out while the torrent is running, the announce loop owns it until it is stopped
+    pub tracker: Option<TrackerSession>,
+    pub downloaders: Vec<Downloader>,
+    workers: Vec<JoinHandle<()>>,
+
+    result_sender: Sender<PieceResult>,
+    result_receiver: Receiver<PieceResult>,
+    //Connections accepted by the listener for this torrent
+    inbound_sender: Sender<Client>,
+    inbound_receiver: Receiver<Client>,
+
+    tracker_commands: Option<Sender<TrackerCommand>>,
+    tracker_task: Option<JoinHandle<TrackerSession>>,
+
+    dht: Option<Arc<DhtNode>>,
+    dht_task: Option<JoinHandle<()>>,
+
+    choker_task: Option<JoinHandle<()>>,
 }
 
 impl Engine {
-    pub fn new(context: EngineContext) -> Self {
-        Self {
+    pub fn new(context: EngineContext) -> SyncResult<Self> {
+        let mut trackers = TrackerManager::new(&context.announce_list);
+        trackers.restore_tracker_ids(&context.tracker_ids);
+        trackers.events = context.events.clone();
+        trackers.settings = context.settings.clone();
+        let request = AnnounceRequest::new(&context)?;
+        let tracker = TrackerSession::new(trackers, request, context.stats.clone());
+
+        let (result_sender, result_receiver) = async_channel::bounded::<PieceResult>(context.pieces.len() + 1);
+        let (inbound_sender, inbound_receiver) = async_channel::unbounded::<Client>();
+
+        Ok(Self {
             context,
-            downloaders: Vec::new()
+            tracker: Some(tracker),
+            downloaders: Vec::new(),
+            workers: Vec::new(),
+
+            result_sender,
+            result_receiver,
+            inbound_sender,
+            inbound_receiver,
+
+            tracker_commands: None,
+            tracker_task: None,
+
+            dht: None,
+            dht_task: None,
+
+            choker_task: None,
+        })
+    }
+
+    pub fn inbound_target(&self) -> InboundTarget {
+        InboundTarget {
+            context: self.context.peer_context(),
+            sender: self.inbound_sender.clone(),
+        }
+    }
+
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.context.listen_port = port;
+
+        if let Some(tracker) = self.tracker.as_mut() {
+            tracker.request.port = port;
+        }
+    }
+
+    pub fn register_extension(&self, extension: Arc<dyn Extension>) -> SyncResult<u8> {
+        self.context.extensions.register(extension)
+    }
+
+    //Looks for peers on the DHT too, unless the torrent is private
+    pub fn set_dht(&mut self, dht: Arc<DhtNode>) {
+        if self.context.private {
+            return;
         }
+
+        self.dht = Some(dht);
     }
 
     pub async fn download_torrent(&mut self) -> SyncResult<()> {
-        println!("[Engine - download_torrent] Starting download");
-        let (work_sender, work_receiver) = async_channel::bounded::<PieceWork>(self.context.pieces.len() + 1);
-        let (result_sender, result_receiver) = async_channel::bounded::<PieceResult>(self.context.pieces.len() + 1);
-        println!("[Engine - download_torrent] Created channels");
+        let peer_receiver = self.start_tracker()?;
+        self.start_choker();
+
+        let mut connect = time::interval(CONNECT_INTERVAL);
+        let mut downloaded_pieces = self.context.pieces.len() - self.context.picker.remaining()?;
+        //A torrent resumed complete was already announced as completed
+        let resumed_complete = downloaded_pieces == self.context.pieces.len();
+        let mut last_save = Instant::now();
 
-        for (index, hash) in self.context.pieces.iter().enumerate() {
-            let length = calculator::calculate_piece_size(self.context.length, self.context.piece_length, index as u32);
-            let piece_work = PieceWork::new(index as u32, hash.clone(), length);
+        while downloaded_pieces < self.context.pieces.len() {
+            tokio::select! {
+                Ok(peer) = peer_receiver.recv() => {
+                    self.context.connections.add_candidate(peer)?;
+                    self.dial_candidates()?;
+                },
+                Ok(client) = self.inbound_receiver.recv() => self.spawn_downloader(client.peer.clone(), Some(client)),
+                //A dial ended or a connection dropped
+                _ = self.context.connections.changed() => self.dial_candidates()?,
+                _ = connect.tick() => {
+                    self.dial_candidates()?;
+                    self.request_peers().await?;
+                },
+                piece_result = self.result_receiver.recv() => {
+                    let piece_result = piece_result?;
+                    let storage = self.context.storage.clone();
+                    let index = piece_result.index;
+                    let length = piece_result.data.len() as u64;
+                    if let Err(error) = tokio::task::spawn_blocking(move || storage.write_piece(index, &piece_result.data)).await? {
+                        self.context.events.emit(Event::StorageError { info_hash: self.context.info_hash.clone(), message: error.to_string() });
+                        return Err(error);
+                    }
+
+                    self.context.have.write().map_err(|_| Error::Poisoned("Have bitfield"))?.set_piece(index);
+                    self.context.stats.add_downloaded(length);
+                    self.context.stats.remove_left(length);
+                    downloaded_pieces += 1;
+                    self.context.events.emit(Event::PieceFinished { info_hash: self.context.info_hash.clone(), index });
 
-            work_sender.send(piece_work).await?;
-            println!("[Engine - download_torrent] Work sent for piece {}", index);
+                    if last_save.elapsed() >= RESUME_SAVE_INTERVAL {
+                        self.save_resume().await;
+                        last_save = Instant::now();
+                    }
+                },
+            }
         }
 
-        let peers = tracker::request_peers(&self.context).await?;
-        println!("[Engine - download_torrent] Received peers");
+        self.save_resume().await;
 
-        for peer in peers {
-            let work_sender = work_sender.clone();
-            let result_sender = result_sender.clone();
-            let work_receiver = work_receiver.clone();
+        if resumed_complete {
+            return Ok(());
+        }
 
-            let downloader = Downloader::new(peer, self.context.info_hash.clone(), work_sender, result_sender, work_receiver);
-            self.downloaders.push(downloader);
+        self.context.events.emit(Event::TorrentFinished { info_hash: self.context.info_hash.clone() });
+        if let Some(commands) = &self.tracker_commands {
+            commands.send(TrackerCommand::Completed).await?;
         }
-        println!("[Engine - download_torrent] Created downloaders");
 
-        for downloader in self.downloaders.iter() {
-            let downloader = downloader.clone();
-            //let downloader = self.downloaders.get(0).unwrap().clone();
-            let _ = tokio::spawn(async move {
-                let result = downloader.start_worker().await;
+        Ok(())
+    }
 
-                if result.is_err() {
-                    println!("[Engine - download_torrent] Failed to start worker: {}", result.as_ref().unwrap_err());
-                }
+    //Downloads what is missing then seeds, until stopped
+    pub async fn start(&mut self) -> SyncResult<()> {
+        self.download_torrent().await?;
+        self.seed().await
+    }
 
-                return result
-            }).await??;
+    //Serves the peers connecting to us until the listener stops handing them over
+    pub async fn seed(&mut self) -> SyncResult<()> {
+        if self.tracker_commands.is_none() {
+            self.start_tracker()?;
         }
-        println!("[Engine - download_torrent] Spawned downloaders");
+        self.start_choker();
 
-        let mut downloaded_pieces = 0;
+        while let Ok(client) = self.inbound_receiver.recv().await {
+            self.spawn_downloader(client.peer.clone(), Some(client));
+        }
 
-        while downloaded_pieces < self.context.pieces.len() {
-            let piece_result = result_receiver.recv().await?;
-            println!("[Engine - download_torrent] Received piece result for piece {}", piece_result.index);
-            let (begin, _end) = calculator::calculate_bounds_for_piece(self.context.length, self.context.piece_length, piece_result.index);
+        Ok(())
+    }
+
+    //Drops every connection and leaves the swarm, the engine can be started again afterwards
+    pub async fn stop(&mut self) -> SyncResult<()> {
+        if let Some(task) = self.dht_task.take() {
+            task.abort();
+        }
+
+        for worker in self.workers.drain(..) {
+            worker.abort();
+        }
+        self.downloaders.clear();
+        self.context.connections.reset()?;
 
-            self.context.destination.seek(SeekFrom::Start(begin as u64)).await?;
-            println!("[Engine - download_torrent] Seeking to {}", begin);
-            self.context.destination.write_all(&piece_result.data).await?;
-            println!("[Engine - download_torrent] Writing piece {}", piece_result.index);
-            downloaded_pieces += 1;
+        //Pieces finished by the aborted workers but not written yet are downloaded again
+        while self.result_receiver.try_recv().is_ok() {}
+        let have = self.context.have.read().map_err(|_| Error::Poisoned("Have bitfield"))?.clone();
+        self.context.picker.reset(&have)?;
 
-            let percentage = (downloaded_pieces as f64 / self.context.pieces.len() as f64) * 100.0;
-            println!("[Engine - download_torrent] Downloaded piece: {} of {} ({}%)", downloaded_pieces, self.context.pieces.len(), percentage);
+        if let Some(task) = self.choker_task.take() {
+            task.abort();
         }
 
-        work_sender.close();
-        work_receiver.close();
+        if let Some(commands) = self.tracker_commands.take() {
+            commands.send(TrackerCommand::Stopped).await?;
+        }
+
+        if let Some(task) = self.tracker_task.take() {
+            let tracker = task.await?;
+            self.context.tracker_ids = tracker.trackers.tracker_ids();
+            self.tracker = Some(tracker);
+        }
+
+        self.save_resume().await;
+
+        Ok(())
+    }
+
+    //A failed save only costs a longer download next time, it never stops the torrent
+    async fn save_resume(&self) {
+        if let Err(error) = self.context.save_resume().await {
+            self.context.events.emit(Event::StorageError { info_hash: self.context.info_hash.clone(), message: error.to_string() });
+        }
+    }
+
+    //Events of this torrent only
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Event> {
+        self.context.events.subscribe()
+    }
+
+    fn start_tracker(&mut self) -> SyncResult<Receiver<Peer>> {
+        let tracker = self.tracker.take().ok_or(Error::State("Tracker session is already running"))?;
+        let (peer_sender, peer_receiver) = async_channel::unbounded::<Peer>();
+        let (command_sender, command_receiver) = async_channel::unbounded::<TrackerCommand>();
+
+        if let Some(dht) = self.dht.clone() {
+            let info_hash = tracker.request.info_hash;
+            let port = tracker.request.port;
+            self.dht_task = Some(tokio::spawn(Engine::run_dht(dht, info_hash, port, peer_sender.clone(), self.context.events.clone())));
+        }
+
+        self.tracker_task = Some(tokio::spawn(tracker.run(peer_sender, command_receiver)));
+        self.tracker_commands = Some(command_sender);
+
+        Ok(peer_receiver)
+    }
+
+    fn start_choker(&mut self) {
+        if self.choker_task.is_none() {
+            let choker = self.context.choker.clone();
+            self.choker_task = Some(tokio::spawn(choker.run(self.context.picker.clone(), self.context.settings.clone())));
+        }
+    }
+
+    async fn run_dht(dht: Arc<DhtNode>, info_hash: SizedBytes, port: u16, peer_sender: Sender<Peer>, events: EventBus) {
+        loop {
+            match dht.announce(info_hash, port).await {
+                Ok(peers) => {
+                    events.emit(Event::DhtReply { info_hash: hex::encode(info_hash), peers: peers.len() });
+                    for peer in peers {
+                        if peer_sender.send(peer).await.is_err() {
+                            return;
+                        }
+                    }
+                },
+                Err(error) => events.emit(Event::DhtError { info_hash: Some(hex::encode(info_hash)), message: error.to_string() }),
+            }
+
+            tokio::time::sleep(DHT_ANNOUNCE_INTERVAL).await;
+        }
+    }
+
+    //Dials candidates until the half-open or the connection limit is reached
+    fn dial_candidates(&mut self) -> SyncResult<()> {
+        let settings = self.context.settings.get();
+
+        while let Some(peer) = self.context.connections.next_dial(&settings)? {
+            self.spawn_downloader(peer, None);
+        }
 
         Ok(())
     }
+
+    //The tracker session decides when it is allowed to announce again
+    async fn request_peers(&self) -> SyncResult<()> {
+        let commands = match &self.tracker_commands {
+            Some(commands) => commands,
+            None => return Ok(()),
+        };
+
+        if self.context.connections.wants_peers(&self.context.settings.get())? {
+            commands.send(TrackerCommand::Reannounce).await?;
+        }
+
+        Ok(())
+    }
+
+    fn spawn_downloader(&mut self, peer: Peer, client: Option<Client>) {
+        //Finished connections are dropped from both lists at once so that they stay aligned
+        let running = self.workers.iter().map(|worker| !worker.is_finished()).collect::<Vec<_>>();
+        let mut keep = running.iter();
+        self.downloaders.retain(|_| *keep.next().unwrap_or(&true));
+        let mut keep = running.iter();
+        self.workers.retain(|_| *keep.next().unwrap_or(&true));
+
+        let downloader = Downloader::new(peer, self.context.peer_context(), self.result_sender.clone());
+        self.downloaders.push(downloader.clone());
+
+        //The worker reports how the connection ended through the events
+        let worker = tokio::spawn(async move {
+            let _ = match client {
+                Some(client) => downloader.start_worker_with(client).await,
+                None => downloader.start_worker().await,
+            };
+        });
+        self.workers.push(worker);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,571 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use rand::seq::SliceRandom;
+use tokio::sync::Notify;
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::types::bitfield::BitField;
+use crate::types::piece::{PieceProgress, PieceWork};
+
+//Pieces picked at random before switching to rarest first, so we quickly have something to trade
+const RANDOM_FIRST_PIECES: usize = 4;
+//In endgame a piece is downloaded from at most this many peers at once, bounding the duplicated traffic
+const MAX_ENDGAME_REQUESTERS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceState {
+    Missing,
+    InProgress,
+    Done,
+}
+
+pub struct PickerState {
+    pub pieces: Vec<PieceWork>,
+    pub states: Vec<PieceState>,
+    //Number of connected peers having each piece
+    pub availability: Vec<u32>,
+    //Number of downloaders working on each piece, more than one only in endgame
+    pub requesters: Vec<u32>,
+    //Blocks of the pieces downloaded from several peers at once, by piece index
+    pub endgame: HashMap<u32, EndgameBlocks>,
+    pub done: usize,
+}
+
+//Blocks of a piece in endgame, each one is only needed from the first peer delivering it
+#[derive(Debug, Default)]
+pub struct EndgameBlocks {
+    //Peers each block is requested from, by begin
+    pub requests: HashMap<u32, u32>,
+    //Blocks received from any of them, by begin
+    pub received: HashMap<u32, Vec<u8>>,
+}
+
+//Decides which piece each downloader works on, shared by every peer of a torrent
+pub struct PiecePicker {
+    state: Mutex<PickerState>,
+    //Woken when pieces become available again, like when a download is aborted
+    released: Notify,
+    //Woken when a block of an endgame piece was delivered while other peers still had it requested
+    landed: Notify,
+}
+
+impl EndgameBlocks {
+    fn forget_request(&mut self, begin: u32) {
+        if let Some(requests) = self.requests.get_mut(&begin) {
+            *requests = requests.saturating_sub(1);
+            if *requests == 0 {
+                self.requests.remove(&begin);
+            }
+        }
+    }
+}
+
+impl PiecePicker {
+    pub fn new(pieces: Vec<PieceWork>, have: &BitField) -> Self {
+        let states = pieces.iter()
+            .map(|piece| if have.has_piece(piece.index) { PieceState::Done } else { PieceState::Missing })
+            .collect::<Vec<_>>();
+        let done = states.iter().filter(|state| **state == PieceState::Done).count();
+
+        Self {
+            state: Mutex::new(PickerState {
+                availability: vec![0; pieces.len()],
+                requesters: vec![0; pieces.len()],
+                endgame: HashMap::new(),
+                pieces,
+                states,
+                done,
+            }),
+            released: Notify::new(),
+            landed: Notify::new(),
+        }
+    }
+
+    //Starts over from a new set of verified pieces, only while no peer is connected
+    pub fn reset(&self, have: &BitField) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        for index in 0..state.pieces.len() {
+            state.states[index] = if have.has_piece(index as u32) { PieceState::Done } else { PieceState::Missing };
+            state.requesters[index] = 0;
+            state.availability[index] = 0;
+        }
+        state.endgame.clear();
+        state.done = state.states.iter().filter(|state| **state == PieceState::Done).count();
+
+        Ok(())
+    }
+
+    fn lock(&self) -> SyncResult<std::sync::MutexGuard<'_, PickerState>> {
+        self.state.lock().map_err(|_| Error::Poisoned("Piece picker"))
+    }
+
+    pub fn add_peer(&self, bitfield: &BitField) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        for index in 0..state.availability.len() {
+            if bitfield.has_piece(index as u32) {
+                state.availability[index] += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_peer(&self, bitfield: &BitField) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        for index in 0..state.availability.len() {
+            if bitfield.has_piece(index as u32) {
+                state.availability[index] = state.availability[index].saturating_sub(1);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add_have(&self, index: u32) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        if let Some(availability) = state.availability.get_mut(index as usize) {
+            *availability += 1;
+        }
+
+        Ok(())
+    }
+
+    //Hands out a missing piece the peer has, the last one it suggested first, then the rarest once the first pieces are done
+    pub fn pick(&self, bitfield: &BitField, suggested: &[u32]) -> SyncResult<Option<PieceWork>> {
+        let mut state = self.lock()?;
+
+        //Peers suggest pieces they have in their cache, those are the cheapest for them to send
+        let suggestion = suggested.iter().rev()
+            .map(|index| *index as usize)
+            .find(|index| state.states.get(*index) == Some(&PieceState::Missing) && bitfield.has_piece(*index as u32));
+
+        let index = match suggestion.or_else(|| PiecePicker::pick_missing(&state, bitfield)) {
+            Some(index) => index,
+            None => return Ok(PiecePicker::pick_endgame(&mut state, bitfield)),
+        };
+
+        state.states[index] = PieceState::InProgress;
+        state.requesters[index] += 1;
+
+        Ok(Some(state.pieces[index]))
+    }
+
+    fn pick_missing(state: &PickerState, bitfield: &BitField) -> Option<usize> {
+        let candidates = (0..state.pieces.len())
+            .filter(|index| state.states[*index] == PieceState::Missing && bitfield.has_piece(*index as u32))
+            .collect::<Vec<_>>();
+
+        let candidates = match state.done < RANDOM_FIRST_PIECES {
+            true => candidates,
+            false => {
+                let rarest = candidates.iter().map(|index| state.availability[*index]).min().unwrap_or(0);
+                candidates.into_iter().filter(|index| state.availability[*index] == rarest).collect()
+            },
+        };
+
+        candidates.choose(&mut rand::thread_rng()).copied()
+    }
+
+    //Once every missing piece is being downloaded, idle peers help with the ones they have
+    fn pick_endgame(state: &mut PickerState, bitfield: &BitField) -> Option<PieceWork> {
+        if state.states.contains(&PieceState::Missing) {
+            return None;
+        }
+
+        let candidates = (0..state.pieces.len())
+            .filter(|index| state.states[*index] == PieceState::InProgress && bitfield.has_piece(*index as u32))
+            .filter(|index| state.requesters[*index] < MAX_ENDGAME_REQUESTERS)
+            .collect::<Vec<_>>();
+
+        let least_requested = candidates.iter().map(|index| state.requesters[*index]).min()?;
+        let candidates = candidates.into_iter().filter(|index| state.requesters[*index] == least_requested).collect::<Vec<_>>();
+
+        let index = *candidates.choose(&mut rand::thread_rng())?;
+        state.requesters[index] += 1;
+        //From now on its blocks are shared between the peers downloading it
+        state.endgame.entry(index as u32).or_default();
+
+        Some(state.pieces[index])
+    }
+
+    //A downloader stopped working on the piece, it goes back to the missing ones when nobody else is on it
+    pub fn abort(&self, index: u32) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        if let Some(requesters) = state.requesters.get_mut(index as usize) {
+            *requesters = requesters.saturating_sub(1);
+        }
+
+        if state.states.get(index as usize) == Some(&PieceState::InProgress) && state.requesters[index as usize] == 0 {
+            state.states[index as usize] = PieceState::Missing;
+            state.endgame.remove(&index);
+        }
+        drop(state);
+
+        self.released.notify_waiters();
+
+        Ok(())
+    }
+
+    //Returns false when the piece was already done
+    pub fn complete(&self, index: u32) -> SyncResult<bool> {
+        let mut state = self.lock()?;
+
+        let completed = state.states.get(index as usize).is_some_and(|state| *state != PieceState::Done);
+        if completed {
+            state.states[index as usize] = PieceState::Done;
+            state.requesters[index as usize] = 0;
+            state.done += 1;
+        }
+        state.endgame.remove(&index);
+        drop(state);
+
+        //Downloaders waiting for work may be done too
+        self.released.notify_waiters();
+
+        Ok(completed)
+    }
+
+    //Counts a request for a block of an endgame piece, false when another peer delivered it already
+    pub fn request_block(&self, index: u32, begin: u32) -> SyncResult<bool> {
+        let mut state = self.lock()?;
+
+        let blocks = match state.endgame.get_mut(&index) {
+            Some(blocks) => blocks,
+            None => return Ok(true),
+        };
+
+        if blocks.received.contains_key(&begin) {
+            return Ok(false);
+        }
+        *blocks.requests.entry(begin).or_insert(0) += 1;
+
+        Ok(true)
+    }
+
+    //Forgets the pending requests of a peer giving up on an endgame piece
+    pub fn release_blocks(&self, index: u32, pending: &[(u32, u32)]) -> SyncResult<()> {
+        let mut state = self.lock()?;
+
+        if let Some(blocks) = state.endgame.get_mut(&index) {
+            for (begin, _) in pending {
+                blocks.forget_request(*begin);
+            }
+        }
+
+        Ok(())
+    }
+
+    //Shares the blocks of an endgame piece between the peers downloading it, returns the pending blocks another peer delivered first, to be cancelled
+    pub fn sync_blocks(&self, progress: &mut PieceProgress) -> SyncResult<Vec<(u32, u32)>> {
+        let mut state = self.lock()?;
+
+        let blocks = match state.endgame.get_mut(&progress.index) {
+            Some(blocks) => blocks,
+            None => return Ok(Vec::new()),
+        };
+
+        //Blocks this peer delivered, some of them possibly before the piece entered endgame
+        let mut landed = false;
+        for begin in progress.received.iter().copied() {
+            if blocks.received.contains_key(&begin) {
+                continue;
+            }
+
+            let end = (begin + progress.block_length(begin)) as usize;
+            blocks.received.insert(begin, progress.data[begin as usize..end].to_vec());
+            blocks.forget_request(begin);
+            landed |= blocks.requests.contains_key(&begin);
+        }
+
+        //Blocks the other peers delivered
+        let mut cancelled = Vec::new();
+        for (begin, data) in blocks.received.iter() {
+            //Peers may use another block size if the settings changed in between
+            if progress.received.contains(begin) || data.len() as u32 != progress.block_length(*begin) {
+                continue;
+            }
+
+            progress.data[*begin as usize..*begin as usize + data.len()].copy_from_slice(data);
+            progress.downloaded += data.len() as u32;
+            progress.received.push(*begin);
+            progress.retry.retain(|(retry, _)| retry != begin);
+
+            if let Some(position) = progress.pending.iter().position(|(pending, _)| pending == begin) {
+                cancelled.push(progress.pending.remove(position));
+                progress.backlog -= 1;
+            }
+        }
+
+        for (begin, _) in cancelled.iter() {
+            blocks.forget_request(*begin);
+        }
+        drop(state);
+
+        if landed {
+            self.landed.notify_waiters();
+        }
+
+        Ok(cancelled)
+    }
+
+    pub fn is_done(&self, index: u32) -> SyncResult<bool> {
+        let state = self.lock()?;
+
+        Ok(state.states.get(index as usize) == Some(&PieceState::Done))
+    }
+
+    //Whether the peer has a piece we still need, pieces being downloaded from other peers included
+    pub fn wants_any(&self, bitfield: &BitField) -> SyncResult<bool> {
+        let state = self.lock()?;
+
+        Ok((0..state.pieces.len()).any(|index| state.states[index] != PieceState::Done && bitfield.has_piece(index as u32)))
+    }
+
+    pub fn is_complete(&self) -> SyncResult<bool> {
+        let state = self.lock()?;
+
+        Ok(state.done == state.pieces.len())
+    }
+
+    pub fn remaining(&self) -> SyncResult<usize> {
+        let state = self.lock()?;
+
+        Ok(state.pieces.len() - state.done)
+    }
+
+    pub async fn released(&self) {
+        self.released.notified().await
+    }
+
+    pub async fn landed(&self) {
+        self.landed.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picker(count: u32, length: u32) -> PiecePicker {
+        let pieces = (0..count).map(|index| PieceWork::new(index, [0; 20], length)).collect();
+
+        PiecePicker::new(pieces, &BitField::empty(count))
+    }
+
+    fn bitfield(count: u32, pieces: &[u32]) -> BitField {
+        let mut bitfield = BitField::empty(count);
+        pieces.iter().for_each(|index| bitfield.set_piece(*index));
+
+        bitfield
+    }
+
+    //Leaves the random first pieces behind so that the rarest ones are picked from there
+    fn complete_first_pieces(picker: &PiecePicker) {
+        for index in 0..RANDOM_FIRST_PIECES as u32 {
+            assert!(picker.complete(index).unwrap());
+        }
+    }
+
+    #[test]
+    fn picks_only_pieces_the_peer_has() {
+        let picker = picker(8, 16);
+        let peer = bitfield(8, &[2, 5]);
+
+        let mut picked = (0..2).map(|_| picker.pick(&peer, &[]).unwrap().unwrap().index).collect::<Vec<_>>();
+        picked.sort();
+
+        assert_eq!(picked, vec![2, 5]);
+        //Other pieces are still missing, so there is no endgame yet
+        assert!(picker.pick(&peer, &[]).unwrap().is_none());
+        assert!(picker.pick(&BitField::empty(8), &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rarest_first_after_the_first_pieces() {
+        let picker = picker(10, 16);
+        complete_first_pieces(&picker);
+
+        picker.add_peer(&BitField::full(10)).unwrap();
+        picker.add_peer(&bitfield(10, &[4, 5, 7, 8, 9])).unwrap();
+        picker.add_peer(&bitfield(10, &[4, 5, 6, 8, 9])).unwrap();
+
+        let full = BitField::full(10);
+        let mut picked = (0..2).map(|_| picker.pick(&full, &[]).unwrap().unwrap().index).collect::<Vec<_>>();
+        picked.sort();
+        assert_eq!(picked, vec![6, 7]);
+
+        //Pieces being downloaded are not handed out again, the more common ones come after
+        let mut picked = (0..4).map(|_| picker.pick(&full, &[]).unwrap().unwrap().index).collect::<Vec<_>>();
+        picked.sort();
+        assert_eq!(picked, vec![4, 5, 8, 9]);
+    }
+
+    #[test]
+    fn suggestions_win() {
+        let picker = picker(10, 16);
+        complete_first_pieces(&picker);
+        picker.add_peer(&BitField::full(10)).unwrap();
+        picker.add_peer(&bitfield(10, &[4, 5, 6, 7, 8])).unwrap();
+
+        let peer = bitfield(10, &[4, 5, 6, 7, 8, 9]);
+        //The most recent suggestion first, pieces the peer lacks or that are done are skipped
+        let suggested = [3, 5, 7, 1];
+        assert_eq!(picker.pick(&peer, &suggested).unwrap().unwrap().index, 7);
+        assert_eq!(picker.pick(&peer, &suggested).unwrap().unwrap().index, 5);
+        //Rarest first again once every suggestion is taken
+        assert_eq!(picker.pick(&peer, &suggested).unwrap().unwrap().index, 9);
+    }
+
+    #[test]
+    fn availability_follows_the_peers() {
+        let picker = picker(4, 16);
+        let availability = |picker: &PiecePicker| picker.lock().unwrap().availability.clone();
+
+        let first = bitfield(4, &[0, 1]);
+        let second = bitfield(4, &[1, 2]);
+        picker.add_peer(&first).unwrap();
+        picker.add_peer(&second).unwrap();
+        assert_eq!(availability(&picker), vec![1, 2, 1, 0]);
+
+        picker.add_have(3).unwrap();
+        //Out of bounds indexes are ignored
+        picker.add_have(4).unwrap();
+        assert_eq!(availability(&picker), vec![1, 2, 1, 1]);
+
+        picker.remove_peer(&first).unwrap();
+        assert_eq!(availability(&picker), vec![0, 1, 1, 1]);
+
+        //Never goes below zero, even for a peer removed twice
+        picker.remove_peer(&first).unwrap();
+        assert_eq!(availability(&picker), vec![0, 0, 1, 1]);
+    }
+
+    //Requests every block the picker still wants, like the downloader does
+    fn request_all(picker: &PiecePicker, progress: &mut PieceProgress) {
+        while let Some((begin, length)) = progress.next_block() {
+            if picker.request_block(progress.index, begin).unwrap() {
+                progress.pending.push((begin, length));
+                progress.backlog += 1;
+            }
+        }
+    }
+
+    //A block arriving from the peer, like the piece message handling does
+    fn deliver(progress: &mut PieceProgress, begin: u32, byte: u8) {
+        let position = progress.pending.iter().position(|(pending, _)| *pending == begin).unwrap();
+        let (_, length) = progress.pending.remove(position);
+
+        progress.data[begin as usize..(begin + length) as usize].fill(byte);
+        progress.downloaded += length;
+        progress.backlog -= 1;
+        progress.received.push(begin);
+    }
+
+    fn requests(picker: &PiecePicker, index: u32, begin: u32) -> Option<u32> {
+        picker.lock().unwrap().endgame.get(&index).and_then(|blocks| blocks.requests.get(&begin).copied())
+    }
+
+    #[test]
+    fn endgame_caps_the_requesters() {
+        let picker = picker(2, 32);
+        let peer = BitField::full(2);
+
+        picker.pick(&peer, &[]).unwrap().unwrap();
+        picker.pick(&peer, &[]).unwrap().unwrap();
+        //No piece is missing anymore, both are shared until each has the maximum of peers
+        for _ in 0..2 * (MAX_ENDGAME_REQUESTERS - 1) {
+            assert!(picker.pick(&peer, &[]).unwrap().is_some());
+        }
+        assert!(picker.pick(&peer, &[]).unwrap().is_none());
+        assert_eq!(picker.lock().unwrap().requesters, vec![MAX_ENDGAME_REQUESTERS; 2]);
+
+        //A peer giving up frees a place on its piece
+        picker.abort(1).unwrap();
+        assert_eq!(picker.pick(&peer, &[]).unwrap().unwrap().index, 1);
+
+        //Completed pieces leave the endgame
+        picker.complete(0).unwrap();
+        assert!(!picker.lock().unwrap().endgame.contains_key(&0));
+    }
+
+    #[test]
+    fn no_endgame_while_pieces_are_missing() {
+        let picker = picker(2, 32);
+
+        picker.pick(&bitfield(2, &[0]), &[]).unwrap().unwrap();
+        //The other piece is still missing, nobody has it so it cannot be downloaded either
+        assert!(picker.pick(&bitfield(2, &[0]), &[]).unwrap().is_none());
+        assert!(picker.lock().unwrap().endgame.is_empty());
+    }
+
+    #[test]
+    fn endgame_shares_blocks_and_cancels_duplicates() {
+        let picker = picker(1, 48);
+        let peer = BitField::full(1);
+
+        assert_eq!(picker.pick(&peer, &[]).unwrap().unwrap().index, 0);
+        assert_eq!(picker.pick(&peer, &[]).unwrap().unwrap().index, 0);
+
+        let mut first = PieceProgress::new(0, 48, 16);
+        let mut second = PieceProgress::new(0, 48, 16);
+        request_all(&picker, &mut first);
+        request_all(&picker, &mut second);
+        assert_eq!(requests(&picker, 0, 0), Some(2));
+
+        //The first peer delivers a block both asked for, the second one cancels it and takes its data
+        deliver(&mut first, 16, 7);
+        assert!(picker.sync_blocks(&mut first).unwrap().is_empty());
+        assert_eq!(picker.sync_blocks(&mut second).unwrap(), vec![(16, 16)]);
+        assert_eq!(second.downloaded, 16);
+        assert_eq!(second.backlog, 2);
+        assert!(second.received.contains(&16));
+        assert!(second.data[16..32].iter().all(|byte| *byte == 7));
+        assert_eq!(requests(&picker, 0, 16), None);
+
+        //A delivered block is never requested again
+        assert!(!picker.request_block(0, 16).unwrap());
+        second.retry.push((16, 16));
+        assert_eq!(second.next_block(), None);
+
+        //Each peer delivers one of the remaining blocks, both end up with the whole piece
+        deliver(&mut first, 0, 1);
+        deliver(&mut second, 32, 3);
+        assert!(picker.sync_blocks(&mut first).unwrap().is_empty());
+        assert_eq!(picker.sync_blocks(&mut second).unwrap(), vec![(0, 16)]);
+        assert_eq!(picker.sync_blocks(&mut first).unwrap(), vec![(32, 16)]);
+
+        for progress in [&first, &second] {
+            assert_eq!(progress.downloaded, 48);
+            assert!(progress.pending.is_empty());
+            assert_eq!(progress.data, [[1; 16], [7; 16], [3; 16]].concat());
+        }
+    }
+
+    #[test]
+    fn endgame_releases_the_requests_of_a_peer_giving_up() {
+        let picker = picker(1, 32);
+        let peer = BitField::full(1);
+        picker.pick(&peer, &[]).unwrap().unwrap();
+        picker.pick(&peer, &[]).unwrap().unwrap();
+
+        let mut first = PieceProgress::new(0, 32, 16);
+        let mut second = PieceProgress::new(0, 32, 16);
+        request_all(&picker, &mut first);
+        request_all(&picker, &mut second);
+
+        picker.release_blocks(0, &second.pending).unwrap();
+        picker.abort(0).unwrap();
+        assert_eq!(requests(&picker, 0, 0), Some(1));
+        assert_eq!(requests(&picker, 0, 16), Some(1));
+
+        //Once nobody downloads it the piece is missing again, and its endgame blocks are forgotten
+        picker.abort(0).unwrap();
+        assert!(picker.lock().unwrap().endgame.is_empty());
+        assert!(picker.request_block(0, 0).unwrap());
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use crate::connection::bandwidth::{SessionBandwidth, TorrentBandwidth};
+use crate::connection::listener::PeerListener;
+use crate::connection::metadata::MetadataDownloader;
+use crate::dht::node::DhtNode;
+use crate::engine::context::EngineContext;
+use crate::engine::events::{Event, EventBus};
+use crate::engine::picker::PiecePicker;
+use crate::engine::Engine;
+use crate::error::Error;
+use crate::protocol::announce::AnnounceRequest;
+use crate::protocol::manager::TrackerManager;
+use crate::settings::{Settings, SharedSettings};
+use crate::shared::{DHT_BOOTSTRAP_NODES, SizedBytes, SyncResult};
+use crate::storage::backend::Storage;
+use crate::storage::recheck::RecheckReport;
+use crate::types::bencode::MetaInfoFile;
+use crate::types::bitfield::BitField;
+use crate::types::magnet::MagnetLink;
+use crate::types::peer::Peer;
+use crate::types::stats::TransferStats;
+use crate::utils::data::manipulator;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    Downloading,
+    Seeding,
+    Paused,
+    //The engine stopped on its own with this error
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: String,
+    pub state: TorrentState,
+
+    pub pieces: u32,
+    pub completed_pieces: u32,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+struct TorrentEntry {
+    //Locked by the running task, the status is read from shared counters instead
+    engine: Arc<tokio::sync::Mutex<Engine>>,
+    task: Option<JoinHandle<()>>,
+    stop: Option<tokio::sync::oneshot::Sender<()>>,
+    error: Arc<Mutex<Option<String>>>,
+    events: EventBus,
+    //Shared with every connection of the torrent, so its limits can change while it runs
+    bandwidth: Arc<TorrentBandwidth>,
+
+    status: TorrentStatus,
+    source: StatusSource,
+}
+
+//Shared parts of the engine context the status is built from
+struct StatusSource {
+    stats: Arc<TransferStats>,
+    have: Arc<RwLock<BitField>>,
+    picker: Arc<PiecePicker>,
+}
+
+//Long-lived owner of every torrent, each one runs on its own task and shares the listener, the DHT and the bandwidth limits
+pub struct Session {
+    pub listener: PeerListener,
+    pub dht: Option<Arc<DhtNode>>,
+    pub bandwidth: Arc<SessionBandwidth>,
+    //Where resume data is kept, next to the downloaded files when unset
+    pub state_directory: Option<PathBuf>,
+    //Events of every torrent, plus the ones of the session itself
+    pub events: EventBus,
+    //Read by every torrent as it runs, replacing them applies to the running torrents too
+    pub settings: Arc<SharedSettings>,
+    //Generated for every session, so that several of them can run in one process
+    pub peer_id: SizedBytes,
+
+    torrents: tokio::sync::Mutex<HashMap<String, TorrentEntry>>,
+}
+
+impl Session {
+    pub async fn new(settings: Settings, state_directory: Option<PathBuf>) -> SyncResult<Self> {
+        let settings = Arc::new(SharedSettings::new(settings)?);
+        let peer_id = settings.get().generate_peer_id();
+
+        let events = EventBus::new();
+        let listener = PeerListener::bind(settings.get().listen_port, settings.clone(), events.clone()).await?;
+        let dht = Session::bind_dht(listener.port, &events).await;
+        if let Some(dht) = dht.clone() {
+            let events = events.clone();
+            tokio::spawn(async move { Session::bootstrap_dht(&dht, &events).await });
+        }
+
+        Ok(Self {
+            listener,
+            dht,
+            bandwidth: Arc::new(SessionBandwidth::default()),
+            state_directory,
+            events,
+            settings,
+            peer_id,
+
+            torrents: tokio::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    //Every add returns the info hash the torrent is then referred to with, it starts right away
+    //Recheck hashes the files already there when there is no resume data for them, like data copied from another machine
+    pub async fn add_torrent_bytes(&self, bytes: &[u8], destination: PathBuf, recheck: bool) -> SyncResult<String> {
+        let meta_info = MetaInfoFile::from_bytes(bytes)?;
+
+        self.add_meta_info(meta_info, destination, recheck).await
+    }
+
+    pub async fn add_torrent_file(&self, path: PathBuf, destination: PathBuf, recheck: bool) -> SyncResult<String> {
+        let meta_info = MetaInfoFile::from_file(path).await?;
+
+        self.add_meta_info(meta_info, destination, recheck).await
+    }
+
+    //Returns once the metadata was fetched from the peers of the link
+    pub async fn add_magnet(&self, uri: &str, destination: PathBuf, recheck: bool) -> SyncResult<String> {
+        let magnet = MagnetLink::parse(uri)?;
+        self.ensure_new(&magnet.info_hash_hex()).await?;
+
+        //The DHT bootstraps in the background, a link added right away would find it empty
+        if let Some(dht) = self.dht.as_deref().filter(|dht| dht.node_count() == 0) {
+            Session::bootstrap_dht(dht, &self.events).await;
+        }
+
+        let peers = self.find_magnet_peers(&magnet).await?;
+        let info_bytes = MetadataDownloader::new(magnet.info_hash_hex(), self.peer_id).fetch_from_peers(&peers).await?;
+        let meta_info = MetaInfoFile::from_info_bytes(&info_bytes, &magnet.trackers)?;
+
+        self.add_meta_info(meta_info, destination, recheck).await
+    }
+
+    pub async fn add_meta_info(&self, meta_info: MetaInfoFile, destination: PathBuf, recheck: bool) -> SyncResult<String> {
+        //Checked before creating the context, which opens the files and may hash them
+        self.ensure_new(&manipulator::hash_meta_info(&meta_info)?).await?;
+        let context = EngineContext::new(meta_info, destination, self.state_directory.clone()).await?;
+
+        self.add_context(context, recheck).await
+    }
+
+    //Stores the torrent in any storage instead of files under a destination, there is no resume data for it so it starts from nothing unless rechecked
+    pub async fn add_with_storage(&self, meta_info: MetaInfoFile, storage: Arc<dyn Storage>, recheck: bool) -> SyncResult<String> {
+        self.ensure_new(&manipulator::hash_meta_info(&meta_info)?).await?;
+        let context = EngineContext::with_storage(meta_info, storage)?;
+
+        self.add_context(context, recheck).await
+    }
+
+    async fn ensure_new(&self, info_hash: &str) -> SyncResult<()> {
+        if self.torrents.lock().await.contains_key(info_hash) {
+            return Err(Error::DuplicateTorrent(info_hash.to_string()));
+        }
+
+        Ok(())
+    }
+
+    async fn add_context(&self, mut context: EngineContext, recheck: bool) -> SyncResult<String> {
+        context.bandwidth = Arc::new(TorrentBandwidth::new(self.bandwidth.clone()));
+        context.listen_port = self.listener.port;
+        context.events = self.events.torrent();
+        context.settings = self.settings.clone();
+        context.peer_id = self.peer_id;
+
+        //Done before the torrent is in the session, hashing everything may take a while
+        if recheck && !context.resumed {
+            context.recheck(None).await?;
+        }
+
+        //Another add of the same torrent may have finished while this context was created
+        let mut torrents = self.torrents.lock().await;
+        if torrents.contains_key(&context.info_hash) {
+            return Err(Error::DuplicateTorrent(context.info_hash.clone()));
+        }
+
+        let info_hash = context.info_hash.clone();
+        let status = TorrentStatus {
+            info_hash: info_hash.clone(),
+            name: context.name.clone(),
+            state: TorrentState::Paused,
+
+            pieces: context.pieces.len() as u32,
+            completed_pieces: 0,
+            uploaded: 0,
+            downloaded: 0,
+            left: context.length,
+        };
+        let source = StatusSource {
+            stats: context.stats.clone(),
+            have: context.have.clone(),
+            picker: context.picker.clone(),
+        };
+        let events = context.events.clone();
+        let bandwidth = context.bandwidth.clone();
+
+        let mut engine = Engine::new(context)?;
+        engine.set_listen_port(self.listener.port);
+        if let Some(dht) = &self.dht {
+            engine.set_dht(dht.clone());
+        }
+
+        let mut entry = TorrentEntry {
+            engine: Arc::new(tokio::sync::Mutex::new(engine)),
+            task: None,
+            stop: None,
+            error: Arc::new(Mutex::new(None)),
+            events,
+            bandwidth,
+
+            status,
+            source,
+        };
+        self.start_entry(&mut entry).await?;
+        torrents.insert(info_hash.clone(), entry);
+        self.events.emit(Event::TorrentAdded { info_hash: info_hash.clone() });
+
+        Ok(info_hash)
+    }
+
+    pub async fn pause(&self, info_hash: &str) -> SyncResult<()> {
+        let mut torrents = self.torrents.lock().await;
+        let entry = torrents.get_mut(info_hash).ok_or_else(|| Error::UnknownTorrent(info_hash.to_string()))?;
+        self.stop_entry(entry).await?;
+
+        entry.events.emit(Event::TorrentPaused { info_hash: info_hash.to_string() });
+
+        Ok(())
+    }
+
+    pub async fn resume(&self, info_hash: &str) -> SyncResult<()> {
+        let mut torrents = self.torrents.lock().await;
+        let entry = torrents.get_mut(info_hash).ok_or_else(|| Error::UnknownTorrent(info_hash.to_string()))?;
+
+        //A failed torrent can be resumed too
+        if entry.task.as_ref().is_some_and(|task| !task.is_finished()) {
+            return Ok(());
+        }
+        self.stop_entry(entry).await?;
+        self.start_entry(entry).await?;
+
+        entry.events.emit(Event::TorrentResumed { info_hash: info_hash.to_string() });
+
+        Ok(())
+    }
+
+    //Hashes the data on disk again and trusts only that, a running torrent is paused meanwhile then started again
+    pub async fn recheck(&self, info_hash: &str) -> SyncResult<RecheckReport> {
+        let (engine, running) = {
+            let mut torrents = self.torrents.lock().await;
+            let entry = torrents.get_mut(info_hash).ok_or_else(|| Error::UnknownTorrent(info_hash.to_string()))?;
+            let running = entry.task.as_ref().is_some_and(|task| !task.is_finished());
+            self.stop_entry(entry).await?;
+
+            (entry.engine.clone(), running)
+        };
+
+        //The session is not locked while hashing, the torrent cannot start again until the engine is released
+        let report = engine.lock().await.context.recheck(None).await?;
+
+        if running {
+            let mut torrents = self.torrents.lock().await;
+            //The torrent may have been removed or resumed meanwhile
+            if let Some(entry) = torrents.get_mut(info_hash).filter(|entry| entry.task.is_none()) {
+                self.start_entry(entry).await?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    //Deleting the data removes the downloaded files and the resume data, directories are left in place
+    pub async fn remove(&self, info_hash: &str, delete_data: bool) -> SyncResult<()> {
+        let mut entry = self.torrents.lock().await.remove(info_hash).ok_or_else(|| Error::UnknownTorrent(info_hash.to_string()))?;
+        self.stop_entry(&mut entry).await?;
+        entry.events.emit(Event::TorrentRemoved { info_hash: info_hash.to_string() });
+
+        if !delete_data {
+            return Ok(());
+        }
+
+        let engine = entry.engine.lock().await;
+        let mut paths = engine.context.storage.layout().files.iter().map(|file| file.path.clone()).collect::<Vec<_>>();
+        paths.extend(engine.context.resume_path.clone());
+
+        let events = entry.events.clone();
+        let info_hash = info_hash.to_string();
+        tokio::task::spawn_blocking(move || {
+            for path in paths {
+                if let Err(error) = std::fs::remove_file(&path) {
+                    if error.kind() != std::io::ErrorKind::NotFound {
+                        events.emit(Event::StorageError { info_hash: info_hash.clone(), message: format!("Failed to remove {}: {}", path.display(), error) });
+                    }
+                }
+            }
+        }).await?;
+
+        Ok(())
+    }
+
+    //Limits are in bytes per second, 0 means unlimited, the session wide ones are set through the bandwidth of the session
+    pub async fn set_torrent_limits(&self, info_hash: &str, upload: u64, download: u64) -> SyncResult<()> {
+        let torrents = self.torrents.lock().await;
+        let entry = torrents.get(info_hash).ok_or_else(|| Error::UnknownTorrent(info_hash.to_string()))?;
+        entry.bandwidth.limits.set_rates(upload, download);
+
+        Ok(())
+    }
+
+    //Applies to each peer of the torrent on its own, the ones already connected included
+    pub async fn set_peer_limits(&self, info_hash: &str, upload: u64, download: u64) -> SyncResult<()> {
+        let torrents = self.torrents.lock().await;
+        let entry = torrents.get(info_hash).ok_or_else(|| Error::UnknownTorrent(info_hash.to_string()))?;
+        entry.bandwidth.set_peer_rates(upload, download);
+
+        Ok(())
+    }
+
+    pub async fn status(&self, info_hash: &str) -> SyncResult<TorrentStatus> {
+        let torrents = self.torrents.lock().await;
+        let entry = torrents.get(info_hash).ok_or_else(|| Error::UnknownTorrent(info_hash.to_string()))?;
+
+        Session::entry_status(entry)
+    }
+
+    //Events of a single torrent, the session events carry them too
+    pub async fn subscribe_torrent(&self, info_hash: &str) -> SyncResult<broadcast::Receiver<Event>> {
+        let torrents = self.torrents.lock().await;
+        let entry = torrents.get(info_hash).ok_or_else(|| Error::UnknownTorrent(info_hash.to_string()))?;
+
+        Ok(entry.events.subscribe())
+    }
+
+    pub async fn torrents(&self) -> SyncResult<Vec<TorrentStatus>> {
+        let torrents = self.torrents.lock().await;
+
+        torrents.values().map(Session::entry_status).collect()
+    }
+
+    //Stops every torrent, telling the trackers we are leaving
+    pub async fn shutdown(&self) -> SyncResult<()> {
+        let mut torrents = self.torrents.lock().await;
+
+        for entry in torrents.values_mut() {
+            self.stop_entry(entry).await?;
+        }
+
+        Ok(())
+    }
+
+    //Peers from the link itself, its trackers and the DHT
+    async fn find_magnet_peers(&self, magnet: &MagnetLink) -> SyncResult<Vec<Peer>> {
+        let mut peers = magnet.peers.clone();
+
+        if !magnet.trackers.is_empty() {
+            let tiers = magnet.trackers.iter().map(|tracker| vec![tracker.clone()]).collect::<Vec<_>>();
+            let mut trackers = TrackerManager::new(&tiers);
+            trackers.announce_to_all = true;
+            trackers.events = self.events.clone();
+            trackers.settings = self.settings.clone();
+
+            //The size is unknown until the metadata arrives, anything but 0 keeps us from looking like a seeder
+            let mut request = AnnounceRequest::with_info_hash(magnet.info_hash, self.peer_id, 1, &self.settings.get());
+            request.port = self.listener.port;
+
+            //Each tracker reports its own answer or failure
+            if let Ok(response) = trackers.announce(&request).await {
+                peers.extend(response.peers);
+            }
+        }
+
+        if let Some(dht) = &self.dht {
+            match dht.get_peers(magnet.info_hash).await {
+                Ok(found) => {
+                    self.events.emit(Event::DhtReply { info_hash: magnet.info_hash_hex(), peers: found.len() });
+                    peers.extend(found);
+                },
+                Err(error) => self.events.emit(Event::DhtError { info_hash: Some(magnet.info_hash_hex()), message: error.to_string() }),
+            }
+        }
+
+        let mut known = HashSet::new();
+        peers.retain(|peer| known.insert(peer.clone()));
+
+        Ok(peers)
+    }
+
+    //The DHT shares the peer port over UDP, it keeps working without it if the bind fails
+    async fn bind_dht(port: u16, events: &EventBus) -> Option<Arc<DhtNode>> {
+        match DhtNode::bind(SocketAddr::from(([0, 0, 0, 0], port)), events.clone()).await {
+            Ok(dht) => Some(Arc::new(dht)),
+            Err(error) => {
+                events.emit(Event::DhtError { info_hash: None, message: error.to_string() });
+                None
+            }
+        }
+    }
+
+    async fn bootstrap_dht(dht: &DhtNode, events: &EventBus) {
+        let mut nodes = Vec::new();
+        for host in DHT_BOOTSTRAP_NODES {
+            if let Ok(addresses) = tokio::net::lookup_host(host).await {
+                nodes.extend(addresses.filter(|address| address.is_ipv4()));
+            }
+        }
+
+        if let Err(error) = dht.bootstrap(&nodes).await {
+            events.emit(Event::DhtError { info_hash: None, message: error.to_string() });
+        }
+    }
+
+    async fn start_entry(&self, entry: &mut TorrentEntry) -> SyncResult<()> {
+        let engine = entry.engine.clone();
+        self.listener.register(engine.lock().await.inbound_target())?;
+        *entry.error.lock().map_err(|_| Error::Poisoned("Torrent error"))? = None;
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let error = entry.error.clone();
+        let events = entry.events.clone();
+
+        entry.stop = Some(stop);
+        entry.task = Some(tokio::spawn(async move {
+            let mut engine = engine.lock().await;
+
+            let result = tokio::select! {
+                result = engine.start() => result,
+                _ = stopped => Ok(()),
+            };
+
+            if let Err(failure) = engine.stop().await.and(result) {
+                events.emit(Event::TorrentError { info_hash: engine.context.info_hash.clone(), message: failure.to_string() });
+                if let Ok(mut error) = error.lock() {
+                    *error = Some(failure.to_string());
+                }
+            }
+        }));
+
+        Ok(())
+    }
+
+    async fn stop_entry(&self, entry: &mut TorrentEntry) -> SyncResult<()> {
+        self.listener.unregister(&entry.status.info_hash)?;
+
+        if let Some(stop) = entry.stop.take() {
+            let _ = stop.send(());
+        }
+
+        if let Some(task) = entry.task.take() {
+            task.await?;
+        }
+
+        Ok(())
+    }
+
+    fn entry_status(entry: &TorrentEntry) -> SyncResult<TorrentStatus> {
+        let mut status = entry.status.clone();
+        let error = entry.error.lock().map_err(|_| Error::Poisoned("Torrent error"))?.clone();
+
+        status.state = match (&entry.task, error) {
+            (_, Some(error)) => TorrentState::Failed(error),
+            (None, None) => TorrentState::Paused,
+            (Some(_), None) if entry.source.picker.is_complete()? => TorrentState::Seeding,
+            (Some(_), None) => TorrentState::Downloading,
+        };
+        status.completed_pieces = entry.source.have.read().map_err(|_| Error::Poisoned("Have bitfield"))?.count_pieces();
+        status.uploaded = entry.source.stats.uploaded();
+        status.downloaded = entry.source.stats.downloaded();
+        status.left = entry.source.stats.left();
+
+        Ok(status)
+    }
+}
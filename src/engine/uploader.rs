@@ -0,0 +1,144 @@
+use std::sync::atomic::Ordering;
+use crate::connection::client::Client;
+use crate::engine::choker::ChokerCommand;
+use crate::engine::context::PeerContext;
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::types::message::{Message, MessageCode};
+
+impl Client {
+    //Keeps the connection open once we have nothing left to download from this peer
+    pub async fn seed(&mut self, context: &PeerContext) -> SyncResult<()> {
+        self.update_interest(context).await?;
+
+        loop {
+            let we_are_complete = context.have.read().map_err(|_| Error::Poisoned("Have bitfield"))?.is_complete(context.piece_count);
+            if we_are_complete && self.bitfield.is_complete(context.piece_count) {
+                return Ok(());
+            }
+
+            tokio::select! {
+                readable = self.connection.readable() => {
+                    readable.map_err(Error::Network)?;
+                    let message = self.read_message().await?;
+                    self.handle_message(&message, context).await?;
+                },
+                Ok(command) = self.choker_commands.recv() => self.apply_choker_command(command).await?,
+            }
+        }
+    }
+
+    pub async fn apply_choker_command(&mut self, command: ChokerCommand) -> SyncResult<()> {
+        match command {
+            ChokerCommand::Choke if !self.am_choking => self.send_choke().await,
+            ChokerCommand::Unchoke if self.am_choking => self.send_unchoke().await,
+            _ => Ok(()),
+        }
+    }
+
+    //Applies the decisions the choker took since we last looked, without waiting for new ones
+    pub async fn apply_choker_commands(&mut self) -> SyncResult<()> {
+        while let Ok(command) = self.choker_commands.try_recv() {
+            self.apply_choker_command(command).await?;
+        }
+
+        Ok(())
+    }
+
+    //Handles any message received outside of a piece download
+    pub async fn handle_message(&mut self, message: &Message, context: &PeerContext) -> SyncResult<()> {
+        match message.id {
+            MessageCode::MessageKeepAlive => {},
+            MessageCode::MessageChoke => self.choked = true,
+            MessageCode::MessageUnchoke => self.choked = false,
+            MessageCode::MessageHave => {
+                let index = message.parse_have()?;
+                self.receive_have(index, context)?;
+                self.update_interest(context).await?;
+            },
+            //A block arriving after its piece was aborted
+            MessageCode::MessagePiece => {},
+            _ => self.serve_message(message, context).await?,
+        }
+
+        Ok(())
+    }
+
+    //Handles the messages driving our upload side, whatever we are downloading at the time
+    pub async fn serve_message(&mut self, message: &Message, context: &PeerContext) -> SyncResult<()> {
+        match message.id {
+            MessageCode::MessageInterested => {
+                self.peer_interested = true;
+
+                if context.choker.on_interested(&self.slot)? && self.am_choking {
+                    self.send_unchoke().await?;
+                }
+            },
+            MessageCode::MessageNotInterested => {
+                self.peer_interested = false;
+                self.slot.interested.store(false, Ordering::Relaxed);
+            },
+            MessageCode::MessageBitfield => {
+                let bitfield = Client::checked_bitfield(&message.payload, context)?;
+                self.set_bitfield(bitfield, context)?;
+                self.update_interest(context).await?;
+            },
+            MessageCode::MessageRequest => {
+                let (index, begin, length) = message.parse_request()?;
+                self.serve_request(index, begin, length, context).await?;
+            },
+            //Requests are answered as soon as they arrive, there is never a queued one to cancel
+            MessageCode::MessageCancel => {},
+            MessageCode::MessageExtended => self.receive_extended(message, context).await?,
+            MessageCode::MessageHaveAll | MessageCode::MessageHaveNone => {
+                self.receive_fast(message, context)?;
+                self.update_interest(context).await?;
+            },
+            MessageCode::MessageSuggest | MessageCode::MessageAllowedFast => self.receive_fast(message, context)?,
+            //Rejects only matter while downloading a piece, a late one is harmless, anything unexpected is ignored too
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    pub async fn serve_request(&mut self, index: u32, begin: u32, length: u32, context: &PeerContext) -> SyncResult<()> {
+        if self.am_choking && !self.granted_fast.contains(&index) {
+            //Requests sent before a choke reached the peer are dropped, the fast extension wants them rejected explicitly
+            if self.supports_fast() {
+                self.send_reject(index, begin, length).await?;
+            }
+
+            return Ok(());
+        }
+
+        let settings = context.settings.get();
+        if length > settings.max_request_size {
+            return Err(Error::Protocol("Requested block is too large".into()));
+        }
+
+        let has_piece = context.have.read().map_err(|_| Error::Poisoned("Have bitfield"))?.has_piece(index);
+        if !has_piece {
+            if self.supports_fast() {
+                return self.send_reject(index, begin, length).await;
+            }
+
+            return Err(Error::Protocol("Requested a piece we do not have".into()));
+        }
+
+        //Checked here so that a bad range is blamed on the peer and not on our storage
+        if context.storage.layout().map_block(index, begin, length).is_err() {
+            return Err(Error::Protocol("Requested block is out of the piece bounds".into()));
+        }
+
+        let storage = context.storage.clone();
+        let cache = context.cache.clone();
+        let block = tokio::task::spawn_blocking(move || cache.read_block(storage.as_ref(), index, begin, length, settings.read_cache_size)).await??;
+
+        self.send_piece(index, begin, &block).await?;
+        context.stats.add_uploaded(length as u64);
+        self.slot.add_uploaded(length as u64);
+
+        Ok(())
+    }
+}
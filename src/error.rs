@@ -0,0 +1,98 @@
+use std::io;
+use thiserror::Error;
+
+//Every failure of the crate, grouped by who is to blame so callers can react to it
+#[derive(Debug, Error)]
+pub enum Error {
+    //The torrent file, the magnet link or the metadata it points to is invalid
+    #[error("Invalid metainfo: {0}")]
+    MetaInfo(String),
+    //The metainfo parses but describes something we must not store, like a file outside of the destination
+    #[error("Invalid torrent: {0}")]
+    InvalidTorrent(String),
+    #[error("Invalid bencode: {0}")]
+    Bencode(#[from] serde_bencode::Error),
+
+    //The tracker answered but refused the announce, with its failure reason
+    #[error("Tracker failure: {0}")]
+    TrackerFailure(String),
+    #[error("Tracker error: {0}")]
+    Tracker(String),
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    //The peer broke the protocol, the connection is not worth keeping
+    #[error("Peer protocol violation: {0}")]
+    Protocol(String),
+    //The peer sent a piece that does not match its hash
+    #[error("Piece {0} does not match its hash")]
+    HashMismatch(u32),
+    //The peer did not answer in time
+    #[error("{0} timed out")]
+    PeerTimeout(&'static str),
+    //The connection to the peer failed or was dropped
+    #[error("Network error: {0}")]
+    Network(#[source] io::Error),
+
+    //A tracker or a DHT node did not answer in time
+    #[error("{0} timed out")]
+    Timeout(&'static str),
+    //Our own sockets failed, like a port already in use or a tracker host that does not resolve
+    #[error("Socket error: {0}")]
+    Socket(#[source] io::Error),
+
+    #[error("DHT error: {0}")]
+    Dht(String),
+    #[error("Extension error: {0}")]
+    Extension(String),
+
+    //Reading or writing the downloaded files failed, the disk may be full or gone
+    #[error("Disk error: {0}")]
+    Disk(#[source] io::Error),
+    #[error("Storage error: {0}")]
+    Storage(String),
+
+    #[error("Torrent {0} is not in the session")]
+    UnknownTorrent(String),
+    #[error("Torrent {0} is already in the session")]
+    DuplicateTorrent(String),
+    //The call does not fit the current state, like starting what is already running
+    #[error("Invalid state: {0}")]
+    State(&'static str),
+    #[error("Invalid settings: {0}")]
+    Settings(String),
+    //The connection is not wanted, like a second one to the same peer or one to ourselves
+    #[error("Connection refused: {0}")]
+    Refused(&'static str),
+
+    #[error("{0} lock is poisoned")]
+    Poisoned(&'static str),
+    #[error("Channel is closed")]
+    ChannelClosed,
+    #[error("Task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl Error {
+    //Failures caused by the remote peer, dropping it is the answer
+    pub fn is_peer_fault(&self) -> bool {
+        matches!(self, Error::Protocol(_) | Error::HashMismatch(_) | Error::PeerTimeout(_) | Error::Network(_))
+    }
+
+    //Failures of our own disk, every torrent using it is affected
+    pub fn is_storage_fault(&self) -> bool {
+        matches!(self, Error::Disk(_) | Error::Storage(_))
+    }
+}
+
+impl<T> From<async_channel::SendError<T>> for Error {
+    fn from(_: async_channel::SendError<T>) -> Self {
+        Error::ChannelClosed
+    }
+}
+
+impl From<async_channel::RecvError> for Error {
+    fn from(_: async_channel::RecvError) -> Self {
+        Error::ChannelClosed
+    }
+}
@@ -1,7 +1,11 @@
 pub mod connection;
+pub mod dht;
 pub mod types;
 pub mod protocol;
 pub mod serializer;
+pub mod settings;
 pub mod shared;
 pub mod engine;
+pub mod error;
+pub mod storage;
 pub mod utils;
\ No newline at end of file
@@ -1,6 +1,9 @@
 use std::path::PathBuf;
 use tokio::runtime::Builder;
-use bit_torrent_rs::engine::manager::EngineManager;
+use tokio::sync::broadcast::error::RecvError;
+use bit_torrent_rs::engine::session::Session;
+use bit_torrent_rs::settings::Settings;
+use bit_torrent_rs::types::magnet::MagnetLink;
 
 pub fn main() -> std::io::Result<()> {
     let worker_threads = std::env::var("WORKER_THREADS")
@@ -19,11 +22,47 @@ pub fn main() -> std::io::Result<()> {
 }
 
 pub async fn async_bootstrap() -> std::io::Result<()> {
-    let path = PathBuf::from("examples/The Matrix 4 - Resurrections.torrent");
-    let destination = PathBuf::from("The Matrix 4 - Resurrections");
+    let state_directory = std::env::var("STATE_DIRECTORY").ok().map(PathBuf::from);
+    //Hashes the files already in the destination when there is no resume data for them
+    let recheck = std::env::var("RECHECK").is_ok();
+    //TOML or JSON, picked from the extension
+    let settings = match std::env::var("SETTINGS_FILE") {
+        Ok(path) => Settings::load(&PathBuf::from(path)).expect("Failed to load settings"),
+        Err(_) => Settings::default(),
+    };
 
-    let mut manager = EngineManager::new(path, destination).await.expect("Failed to create engine manager");
-    manager.start_engines().await.expect("Failed to start engines");
+    let session = Session::new(settings, state_directory).await.expect("Failed to create session");
+
+    //Events are the only output of the library, print them as they come
+    let mut events = session.events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => println!("{:?}", event),
+                Err(RecvError::Lagged(missed)) => println!("Missed {} events", missed),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    match std::env::args().nth(1) {
+        Some(uri) if uri.starts_with("magnet:") => {
+            let magnet = MagnetLink::parse(&uri).expect("Failed to parse magnet link");
+            let destination = PathBuf::from(magnet.name.unwrap_or_else(|| hex::encode(magnet.info_hash)));
+
+            session.add_magnet(&uri, destination, recheck).await.expect("Failed to add magnet link")
+        },
+        _ => {
+            let path = PathBuf::from("examples/The Matrix 4 - Resurrections.torrent");
+            let destination = PathBuf::from("The Matrix 4 - Resurrections");
+
+            session.add_torrent_file(path, destination, recheck).await.expect("Failed to add torrent")
+        },
+    };
+
+    //Torrents download then seed on their own tasks, until ctrl-c tells the trackers we are leaving
+    tokio::signal::ctrl_c().await?;
+    session.shutdown().await.expect("Failed to shut down session");
 
     Ok(())
 }
@@ -0,0 +1,124 @@
+use rand::Rng;
+use crate::engine::context::EngineContext;
+use crate::error::Error;
+use crate::settings::Settings;
+use crate::shared::{SizedBytes, SyncResult};
+use crate::types::bencode::TrackerResponse;
+use crate::types::peer::Peer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: SizedBytes,
+    pub peer_id: SizedBytes,
+    pub port: u16,
+
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+
+    pub event: AnnounceEvent,
+    pub numwant: i32,
+    pub key: u32,
+    //Echoed back to the tracker that handed it out
+    pub tracker_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceResponse {
+    pub interval: Option<u32>,
+    pub min_interval: Option<u32>,
+    pub tracker_id: Option<String>,
+    pub warning_message: Option<String>,
+
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScrapeResponse {
+    pub complete: u32,
+    pub downloaded: u32,
+    pub incomplete: u32,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+}
+
+impl AnnounceRequest {
+    pub fn new(context: &EngineContext) -> SyncResult<Self> {
+        let info_hash = hex::decode(&context.info_hash).ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| Error::MetaInfo("Info hash is not 20 hex encoded bytes".into()))?;
+
+        let mut request = Self::with_info_hash(info_hash, context.peer_id, context.stats.left(), &context.settings.get());
+        request.port = context.listen_port;
+        request.uploaded = context.stats.uploaded();
+        request.downloaded = context.stats.downloaded();
+
+        Ok(request)
+    }
+
+    //For announces made before the metainfo is known, like when resolving a magnet link
+    pub fn with_info_hash(info_hash: SizedBytes, peer_id: SizedBytes, left: u64, settings: &Settings) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            port: settings.listen_port,
+
+            uploaded: 0,
+            downloaded: 0,
+            left,
+
+            event: AnnounceEvent::Started,
+            numwant: settings.numwant,
+            key: rand::thread_rng().gen(),
+            tracker_id: None,
+        }
+    }
+}
+
+impl TryFrom<TrackerResponse> for AnnounceResponse {
+    type Error = Error;
+
+    fn try_from(response: TrackerResponse) -> SyncResult<Self> {
+        if let Some(reason) = response.failure_reason {
+            return Err(Error::TrackerFailure(reason));
+        }
+
+        let mut peers = Vec::new();
+        if let Some(compact) = response.peers {
+            peers.extend(Peer::from_bytes(compact.as_ref())?);
+        }
+        if let Some(compact) = response.peers6 {
+            peers.extend(Peer::from_bytes_v6(compact.as_ref())?);
+        }
+
+        Ok(Self {
+            interval: response.interval,
+            min_interval: response.min_interval,
+            tracker_id: response.tracker_id,
+            warning_message: response.warning_message,
+
+            complete: response.complete,
+            incomplete: response.incomplete,
+            peers,
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::future;
+use rand::seq::SliceRandom;
+use tokio::time::{self, Instant};
+use crate::engine::events::{Event, EventBus};
+use crate::error::Error;
+use crate::protocol::announce::{AnnounceRequest, AnnounceResponse};
+use crate::protocol::tracker;
+use crate::protocol::udp::{self, UdpTracker};
+use crate::settings::{Settings, SharedSettings};
+use crate::shared::SyncResult;
+
+//The full BEP 15 schedule waits more than two hours on a dead udp tracker, two retries give up after 15 + 30 + 60 seconds
+const FAILOVER_RETRIES: u32 = 2;
+//Time a tier gets to answer, the trackers not tried by then are skipped until the next announce
+const TIER_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+pub struct TrackerEntry {
+    pub url: String,
+    pub tracker_id: Option<String>,
+    //Kept across announces so the connection id can be reused
+    udp: Option<UdpTracker>,
+}
+
+pub struct TrackerManager {
+    pub tiers: Vec<Vec<TrackerEntry>>,
+    //Query every tier at once instead of stopping at the first responsive one
+    pub announce_to_all: bool,
+    //Every tracker answer or failure is reported there
+    pub events: EventBus,
+    pub settings: Arc<SharedSettings>,
+}
+
+impl TrackerEntry {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            tracker_id: None,
+            udp: None,
+        }
+    }
+
+    pub async fn announce(&mut self, request: &AnnounceRequest, settings: &Settings) -> SyncResult<AnnounceResponse> {
+        if !self.url.starts_with("udp://") {
+            let mut request = request.clone();
+            request.tracker_id = self.tracker_id.clone();
+
+            let response = tracker::announce(&self.url, &request, settings).await?;
+            if response.tracker_id.is_some() {
+                self.tracker_id = response.tracker_id.clone();
+            }
+
+            return Ok(response);
+        }
+
+        let udp = match self.udp.as_mut() {
+            Some(udp) => udp,
+            None => {
+                let udp = UdpTracker::connect(&self.url).await?.with_timeout(udp::BASE_TIMEOUT, FAILOVER_RETRIES);
+                self.udp.insert(udp)
+            },
+        };
+
+        udp.announce(request).await
+    }
+}
+
+impl TrackerManager {
+    pub fn new(tiers: &[Vec<String>]) -> Self {
+        let mut rng = rand::thread_rng();
+
+        //BEP 12 asks for every tier to be shuffled once, the order is then kept across announces
+        let tiers = tiers.iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| {
+                let mut entries = tier.iter().cloned().map(TrackerEntry::new).collect::<Vec<_>>();
+                entries.shuffle(&mut rng);
+                entries
+            })
+            .collect();
+
+        Self {
+            tiers,
+            announce_to_all: false,
+            events: EventBus::new(),
+            settings: Arc::new(SharedSettings::default()),
+        }
+    }
+
+    pub fn tracker_ids(&self) -> BTreeMap<String, String> {
+        self.tiers.iter()
+            .flatten()
+            .filter_map(|entry| Some((entry.url.clone(), entry.tracker_id.clone()?)))
+            .collect()
+    }
+
+    //Tracker ids saved by an earlier session, for the trackers still listed
+    pub fn restore_tracker_ids(&mut self, tracker_ids: &BTreeMap<String, String>) {
+        for entry in self.tiers.iter_mut().flatten() {
+            if let Some(tracker_id) = tracker_ids.get(&entry.url) {
+                entry.tracker_id = Some(tracker_id.clone());
+            }
+        }
+    }
+
+    pub async fn announce(&mut self, request: &AnnounceRequest) -> SyncResult<AnnounceResponse> {
+        if self.announce_to_all {
+            return self.announce_all_tiers(request).await;
+        }
+
+        let settings = self.settings.get();
+        for tier in self.tiers.iter_mut() {
+            if let Ok(response) = TrackerManager::announce_tier(tier, request, &self.events, &settings).await {
+                return Ok(response);
+            }
+        }
+
+        Err(Error::Tracker("Every tracker tier failed".into()))
+    }
+
+    async fn announce_all_tiers(&mut self, request: &AnnounceRequest) -> SyncResult<AnnounceResponse> {
+        let events = &self.events;
+        let settings = self.settings.get();
+        let announces = self.tiers.iter_mut().map(|tier| TrackerManager::announce_tier(tier, request, events, &settings));
+        let results = future::join_all(announces).await;
+
+        let mut merged: Option<AnnounceResponse> = None;
+        for result in results {
+            let response = match result {
+                Ok(response) => response,
+                Err(_) => continue,
+            };
+
+            match merged.as_mut() {
+                Some(merged) => {
+                    for peer in response.peers {
+                        if !merged.peers.contains(&peer) {
+                            merged.peers.push(peer);
+                        }
+                    }
+                },
+                None => merged = Some(response),
+            }
+        }
+
+        merged.ok_or_else(|| Error::Tracker("Every tracker tier failed".into()))
+    }
+
+    //Tries the trackers of a tier in order until the tier timeout, the first one to answer is moved to the front of its tier
+    async fn announce_tier(tier: &mut Vec<TrackerEntry>, request: &AnnounceRequest, events: &EventBus, settings: &Settings) -> SyncResult<AnnounceResponse> {
+        let info_hash = hex::encode(request.info_hash);
+        let deadline = Instant::now() + TIER_TIMEOUT;
+
+        for index in 0..tier.len() {
+            let result = match time::timeout_at(deadline, tier[index].announce(request, settings)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    let error = Error::Timeout("Tracker tier");
+                    events.emit(Event::TrackerError { info_hash, url: tier[index].url.clone(), message: error.to_string() });
+
+                    return Err(error);
+                }
+            };
+
+            match result {
+                Ok(response) => {
+                    events.emit(Event::TrackerReply { info_hash, url: tier[index].url.clone(), peers: response.peers.len() });
+                    let entry = tier.remove(index);
+                    tier.insert(0, entry);
+
+                    return Ok(response);
+                },
+                Err(error) => events.emit(Event::TrackerError { info_hash: info_hash.clone(), url: tier[index].url.clone(), message: error.to_string() }),
+            }
+        }
+
+        Err(Error::Tracker("Every tracker in the tier failed".into()))
+    }
+}
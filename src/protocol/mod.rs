@@ -1 +1,5 @@
-pub mod tracker;
\ No newline at end of file
+pub mod announce;
+pub mod manager;
+pub mod session;
+pub mod tracker;
+pub mod udp;
\ No newline at end of file
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_channel::{Receiver, Sender};
+use tokio::time::{self, Instant};
+use crate::engine::events::Event;
+use crate::protocol::announce::{AnnounceEvent, AnnounceRequest};
+use crate::protocol::manager::TrackerManager;
+use crate::shared::SyncResult;
+use crate::types::peer::Peer;
+use crate::types::stats::TransferStats;
+
+//Used until a tracker tells us its own interval
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1800);
+//Delay before retrying when every tracker failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+//Asking for more peers never announces more often than this, whatever the min_interval of the tracker
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerCommand {
+    //The download finished, announce it once
+    Completed,
+    //More peers are needed, announce as soon as min_interval allows
+    Reannounce,
+    Stopped,
+}
+
+pub struct TrackerSession {
+    pub trackers: TrackerManager,
+    //Template for every announce, transfer counters and event are filled in when announcing
+    pub request: AnnounceRequest,
+    pub stats: Arc<TransferStats>,
+
+    pub interval: Duration,
+    pub min_interval: Duration,
+    last_announce: Option<Instant>,
+}
+
+impl TrackerSession {
+    pub fn new(trackers: TrackerManager, request: AnnounceRequest, stats: Arc<TransferStats>) -> Self {
+        Self {
+            trackers,
+            request,
+            stats,
+
+            interval: DEFAULT_INTERVAL,
+            min_interval: Duration::ZERO,
+            last_announce: None,
+        }
+    }
+
+    pub async fn announce(&mut self, event: AnnounceEvent) -> SyncResult<Vec<Peer>> {
+        let mut request = self.request.clone();
+        request.event = event;
+        request.uploaded = self.stats.uploaded();
+        request.downloaded = self.stats.downloaded();
+        request.left = self.stats.left();
+        request.numwant = self.trackers.settings.get().numwant;
+
+        self.last_announce = Some(Instant::now());
+        let response = self.trackers.announce(&request).await?;
+
+        if let Some(warning) = response.warning_message.clone() {
+            self.trackers.events.emit(Event::TrackerWarning { info_hash: hex::encode(request.info_hash), message: warning });
+        }
+
+        if let Some(interval) = response.interval {
+            self.interval = Duration::from_secs(interval as u64);
+        }
+
+        if let Some(min_interval) = response.min_interval {
+            self.min_interval = Duration::from_secs(min_interval as u64);
+        }
+
+        Ok(response.peers)
+    }
+
+    //Announces until stopped, every peer received is forwarded to the engine
+    pub async fn run(mut self, peer_sender: Sender<Peer>, commands: Receiver<TrackerCommand>) -> Self {
+        let mut event = AnnounceEvent::Started;
+
+        loop {
+            let wait = match self.announce(event).await {
+                Ok(peers) => {
+                    for peer in peers {
+                        if peer_sender.send(peer).await.is_err() {
+                            break;
+                        }
+                    }
+
+                    event = AnnounceEvent::None;
+                    self.interval
+                },
+                //Every failed tracker was reported, the event is kept so that started and completed are not lost when a tracker is down
+                Err(_) => RETRY_INTERVAL.max(self.min_interval),
+            };
+
+            let last_announce = self.last_announce.unwrap_or_else(Instant::now);
+            let mut next = last_announce + wait;
+
+            //Commands keep coming while we wait, a stop must never wait for the next announce
+            loop {
+                let command = tokio::select! {
+                    _ = time::sleep_until(next) => break,
+                    command = commands.recv() => command.unwrap_or(TrackerCommand::Stopped),
+                };
+
+                match command {
+                    TrackerCommand::Completed => {
+                        event = AnnounceEvent::Completed;
+                        break;
+                    },
+                    TrackerCommand::Reannounce => next = next.min(last_announce + self.min_interval.max(REANNOUNCE_INTERVAL)),
+                    TrackerCommand::Stopped => {
+                        let _ = self.announce(AnnounceEvent::Stopped).await;
+
+                        return self;
+                    },
+                }
+            }
+        }
+    }
+}
@@ -1,45 +1,66 @@
 use percent_encoding::percent_encode;
 use reqwest::Client;
-use crate::engine::context::EngineContext;
-use crate::shared::{PEER_ID, SyncResult, URL_ENCODE_RESERVED};
+use url::Url;
+use crate::error::Error;
+use crate::protocol::announce::{AnnounceRequest, AnnounceResponse};
+use crate::protocol::udp::UdpTracker;
+use crate::settings::Settings;
+use crate::shared::{SyncResult, URL_ENCODE_RESERVED};
 use crate::types::bencode::TrackerResponse;
-use crate::types::peer::Peer;
-
-pub fn build_tracker_url(context: &EngineContext) -> SyncResult<(String, Vec<(&str, String)>)> {
-    println!("[build_tracker_url] Encoded hex info_hash: {}", percent_encode(context.info_hash.as_ref(), &URL_ENCODE_RESERVED).to_string());
-    println!("[build_tracker_url] Encoded info_hash: {}", percent_encode(&hex::decode(context.info_hash.clone())?, &URL_ENCODE_RESERVED).to_string());
-    let info_hash = percent_encode(&hex::decode(context.info_hash.clone())?, &URL_ENCODE_RESERVED).to_string();
-    let peer_id = PEER_ID.get().ok_or("Failed to get peer id, is it set ?")?.clone();
-    let peer_id = percent_encode(&peer_id, &URL_ENCODE_RESERVED).to_string();
-
-    let query = vec![
-        ("port", "6881".to_string()),
-        ("uploaded", "0".to_string()),
-        ("downloaded", "0".to_string()),
-        ("left", context.length.to_string()),
+
+pub fn build_tracker_url(announce: &str, request: &AnnounceRequest) -> SyncResult<(String, Vec<(&'static str, String)>)> {
+    let info_hash = percent_encode(&request.info_hash, &URL_ENCODE_RESERVED).to_string();
+    let peer_id = percent_encode(&request.peer_id, &URL_ENCODE_RESERVED).to_string();
+
+    let mut query = vec![
+        ("port", request.port.to_string()),
+        ("uploaded", request.uploaded.to_string()),
+        ("downloaded", request.downloaded.to_string()),
+        ("left", request.left.to_string()),
         ("corrupt", "0".to_string()),
-        ("event", "started".to_string()),
-        ("numwant", "200".to_string()),
+        ("numwant", request.numwant.to_string()),
+        ("key", request.key.to_string()),
         ("compact", "1".to_string()),
         ("no_peer_id", "1".to_string()),
     ];
 
-    let url = format!("{}?info_hash={}&peer_id={}", context.announce, info_hash, peer_id);
+    if let Some(event) = request.event.as_str() {
+        query.push(("event", event.to_string()));
+    }
+
+    if let Some(tracker_id) = &request.tracker_id {
+        query.push(("trackerid", tracker_id.clone()));
+    }
+
+    //Some announce urls already carry a query, like passkeys on private trackers
+    let separator = if announce.contains('?') { '&' } else { '?' };
+    let url = format!("{}{}info_hash={}&peer_id={}", announce, separator, info_hash, peer_id);
 
     Ok((url, query))
 }
 
-pub async fn request_peers(context: &EngineContext) -> SyncResult<Vec<Peer>> {
-    let url = build_tracker_url(context)?;
-    println!("[request_peers] Requesting peers from tracker: {}", &url.0);
+pub async fn announce(announce: &str, request: &AnnounceRequest, settings: &Settings) -> SyncResult<AnnounceResponse> {
+    let scheme = Url::parse(announce).map_err(|error| Error::Tracker(error.to_string()))?.scheme().to_string();
+
+    match scheme.as_str() {
+        "udp" => {
+            let mut tracker = UdpTracker::connect(announce).await?;
+
+            tracker.announce(request).await
+        },
+        "http" | "https" => announce_http(announce, request, settings).await,
+        _ => Err(Error::Tracker(format!("Unsupported tracker scheme: {}", scheme))),
+    }
+}
+
+pub async fn announce_http(announce: &str, request: &AnnounceRequest, settings: &Settings) -> SyncResult<AnnounceResponse> {
+    let url = build_tracker_url(announce, request)?;
 
-    let response = Client::new().get(&url.0).query(&url.1).send().await?.error_for_status()?;
-    println!("[request_peers] Received response from tracker");
-    println!("[request_peers] Response code: {}", response.status());
+    let client = Client::builder().timeout(settings.tracker_timeout()).user_agent(settings.user_agent.clone()).build()?;
+    let response = client.get(&url.0).query(&url.1).send().await?.error_for_status()?;
 
     let response = response.bytes().await?;
     let parsed = serde_bencode::from_bytes::<TrackerResponse>(&response)?;
 
-    let peers = parsed.peers.ok_or("Missing peers in tracker response")?;
-    Peer::from_bytes(peers.as_ref())
+    AnnounceResponse::try_from(parsed)
 }
\ No newline at end of file
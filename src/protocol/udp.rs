@@ -0,0 +1,468 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time;
+use url::Url;
+use crate::error::Error;
+use crate::protocol::announce::{AnnounceRequest, AnnounceResponse, ScrapeResponse};
+use crate::shared::{SizedBytes, SyncResult};
+use crate::types::peer::Peer;
+use crate::utils::data::manipulator;
+
+//Magic constant identifying the connect request, see BEP 15
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+//A connection id can be reused for one minute after it was received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+//The spec waits 15 * 2 ^ n seconds before retransmitting, for n up to 8
+pub const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRIES: u32 = 8;
+//Scrape requests are limited to about 74 info hashes per packet
+const MAX_SCRAPE_HASHES: usize = 74;
+
+fn response_u32(response: &[u8], offset: usize) -> SyncResult<u32> {
+    manipulator::read_u32(response, offset).ok_or_else(|| Error::Tracker("Udp response is too short".into()))
+}
+
+pub struct UdpTracker {
+    pub address: SocketAddr,
+    socket: UdpSocket,
+
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retries: u32,
+}
+
+impl UdpTracker {
+    pub async fn connect(announce: &str) -> SyncResult<Self> {
+        let url = Url::parse(announce).map_err(|error| Error::Tracker(error.to_string()))?;
+        if url.scheme() != "udp" {
+            return Err(Error::Tracker("Tracker url is not an udp url".into()));
+        }
+
+        let host = url.host_str().ok_or_else(|| Error::Tracker("Missing host in tracker url".into()))?;
+        let port = url.port().ok_or_else(|| Error::Tracker("Missing port in tracker url".into()))?;
+        //Hosts given as IPv6 literals are bracketed in urls
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let address = tokio::net::lookup_host((host, port)).await.map_err(Error::Socket)?.next().ok_or_else(|| Error::Tracker("Failed to resolve tracker host".into()))?;
+
+        Self::bind(address).await
+    }
+
+    pub async fn bind(address: SocketAddr) -> SyncResult<Self> {
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local).await.map_err(Error::Socket)?;
+
+        Ok(Self {
+            address,
+            socket,
+
+            connection: None,
+            base_timeout: BASE_TIMEOUT,
+            max_retries: MAX_RETRIES,
+        })
+    }
+
+    pub fn with_timeout(mut self, base_timeout: Duration, max_retries: u32) -> Self {
+        self.base_timeout = base_timeout;
+        self.max_retries = max_retries;
+
+        self
+    }
+
+    pub async fn announce(&mut self, request: &AnnounceRequest) -> SyncResult<AnnounceResponse> {
+        let response = self.request(ACTION_ANNOUNCE, |connection_id, transaction_id| {
+            let mut packet = Vec::with_capacity(98);
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+            packet.extend_from_slice(&transaction_id.to_be_bytes());
+            packet.extend_from_slice(&request.info_hash);
+            packet.extend_from_slice(&request.peer_id);
+            packet.extend_from_slice(&request.downloaded.to_be_bytes());
+            packet.extend_from_slice(&request.left.to_be_bytes());
+            packet.extend_from_slice(&request.uploaded.to_be_bytes());
+            packet.extend_from_slice(&(request.event as u32).to_be_bytes());
+            //Let the tracker use the address the packet comes from
+            packet.extend_from_slice(&0u32.to_be_bytes());
+            packet.extend_from_slice(&request.key.to_be_bytes());
+            packet.extend_from_slice(&request.numwant.to_be_bytes());
+            packet.extend_from_slice(&request.port.to_be_bytes());
+
+            packet
+        }).await?;
+
+        self.parse_announce(&response)
+    }
+
+    pub async fn scrape(&mut self, info_hashes: &[SizedBytes]) -> SyncResult<Vec<ScrapeResponse>> {
+        if info_hashes.len() > MAX_SCRAPE_HASHES {
+            return Err(Error::Tracker("Too many info hashes for a single scrape request".into()));
+        }
+
+        let response = self.request(ACTION_SCRAPE, |connection_id, transaction_id| {
+            let mut packet = Vec::with_capacity(16 + 20 * info_hashes.len());
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+            packet.extend_from_slice(&transaction_id.to_be_bytes());
+            info_hashes.iter().for_each(|info_hash| packet.extend_from_slice(info_hash));
+
+            packet
+        }).await?;
+
+        let mut entries = Vec::with_capacity(info_hashes.len());
+        for entry in response[8..].chunks_exact(12).take(info_hashes.len()) {
+            entries.push(ScrapeResponse {
+                complete: response_u32(entry, 0)?,
+                downloaded: response_u32(entry, 4)?,
+                incomplete: response_u32(entry, 8)?,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    //Connecting and sending share one retry counter, a silent tracker is given up on after max_retries timeouts in total
+    async fn request(&mut self, action: u32, build: impl Fn(u64, u32) -> Vec<u8>) -> SyncResult<Vec<u8>> {
+        let mut attempt = 0;
+
+        loop {
+            if attempt > self.max_retries {
+                return Err(Error::Tracker("Udp tracker did not answer".into()));
+            }
+
+            //Rebuilt on every attempt, the connection id may have expired while waiting
+            let connection_id = match self.connection_id(attempt).await? {
+                Some(connection_id) => connection_id,
+                None => {
+                    attempt += 1;
+                    continue;
+                }
+            };
+
+            let transaction_id = rand::random::<u32>();
+            self.socket.send_to(&build(connection_id, transaction_id), self.address).await.map_err(Error::Socket)?;
+
+            match self.receive(action, transaction_id, attempt).await? {
+                Some(response) => return Ok(response),
+                None => attempt += 1,
+            }
+        }
+    }
+
+    //Reuses the last connection id while it is valid, None when the tracker did not answer in time
+    async fn connection_id(&mut self, attempt: u32) -> SyncResult<Option<u64>> {
+        if let Some((connection_id, received)) = self.connection {
+            if received.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(Some(connection_id));
+            }
+        }
+
+        let transaction_id = rand::random::<u32>();
+
+        let mut packet = Vec::with_capacity(16);
+        packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+
+        self.socket.send_to(&packet, self.address).await.map_err(Error::Socket)?;
+
+        let response = match self.receive(ACTION_CONNECT, transaction_id, attempt).await? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+
+        if response.len() < 16 {
+            return Err(Error::Tracker("Connect response is too short".into()));
+        }
+
+        let connection_id = manipulator::read_u64(&response, 8).ok_or_else(|| Error::Tracker("Udp response is too short".into()))?;
+        self.connection = Some((connection_id, Instant::now()));
+
+        Ok(Some(connection_id))
+    }
+
+    async fn receive(&mut self, action: u32, transaction_id: u32, attempt: u32) -> SyncResult<Option<Vec<u8>>> {
+        let timeout = self.base_timeout * 2u32.pow(attempt);
+        let deadline = time::Instant::now() + timeout;
+        let mut buffer = vec![0; 65536];
+
+        loop {
+            let received = match time::timeout_at(deadline, self.socket.recv_from(&mut buffer)).await {
+                Ok(received) => received.map_err(Error::Socket)?,
+                Err(_) => return Ok(None),
+            };
+
+            let (length, from) = received;
+            if from != self.address || length < 8 {
+                continue;
+            }
+
+            let response = &buffer[..length];
+            let received_action = response_u32(response, 0)?;
+            let received_transaction = response_u32(response, 4)?;
+
+            //Stale answers to a previous retransmission are dropped
+            if received_transaction != transaction_id {
+                continue;
+            }
+
+            if received_action == ACTION_ERROR {
+                let message = String::from_utf8_lossy(&response[8..]);
+                return Err(Error::TrackerFailure(message.into_owned()));
+            }
+
+            if received_action != action {
+                return Err(Error::Tracker("Udp tracker answered with an unexpected action".into()));
+            }
+
+            return Ok(Some(response.to_vec()));
+        }
+    }
+
+    fn parse_announce(&self, response: &[u8]) -> SyncResult<AnnounceResponse> {
+        if response.len() < 20 {
+            return Err(Error::Tracker("Announce response is too short".into()));
+        }
+
+        let interval = response_u32(response, 8)?;
+        let incomplete = response_u32(response, 12)?;
+        let complete = response_u32(response, 16)?;
+
+        //Peers are returned in the address family the request was sent over
+        let peers = match self.address {
+            SocketAddr::V4(_) => Peer::from_bytes(&response[20..])?,
+            SocketAddr::V6(_) => Peer::from_bytes_v6(&response[20..])?,
+        };
+
+        Ok(AnnounceResponse {
+            interval: Some(interval),
+            complete: Some(complete),
+            incomplete: Some(incomplete),
+            peers,
+
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::*;
+    use crate::settings::Settings;
+
+    const CONNECTION_ID: u64 = 0x1122334455667788;
+
+    //Short timeouts, a lost packet costs a few milliseconds
+    async fn tracker(server: &UdpSocket) -> UdpTracker {
+        UdpTracker::bind(server.local_addr().unwrap()).await.unwrap().with_timeout(Duration::from_millis(50), 2)
+    }
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest::with_info_hash([7; 20], [9; 20], 1000, &Settings::default())
+    }
+
+    async fn receive(server: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buffer = vec![0; 2048];
+        let (length, from) = server.recv_from(&mut buffer).await.unwrap();
+        buffer.truncate(length);
+
+        (buffer, from)
+    }
+
+    fn header(action: u32, transaction_id: &[u8]) -> Vec<u8> {
+        let mut packet = action.to_be_bytes().to_vec();
+        packet.extend_from_slice(transaction_id);
+        packet
+    }
+
+    //Answers a connect request, and returns the next packet
+    async fn accept_connect(server: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let (packet, from) = receive(server).await;
+        assert_eq!(&packet[0..8], &PROTOCOL_ID.to_be_bytes());
+        assert_eq!(&packet[8..12], &ACTION_CONNECT.to_be_bytes());
+
+        let mut answer = header(ACTION_CONNECT, &packet[12..16]);
+        answer.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+        server.send_to(&answer, from).await.unwrap();
+
+        let (packet, from) = receive(server).await;
+        assert_eq!(&packet[0..8], &CONNECTION_ID.to_be_bytes());
+        (packet, from)
+    }
+
+    async fn announce_answer(server: &UdpSocket, peers: &[u8]) {
+        let (packet, from) = accept_connect(server).await;
+        assert_eq!(&packet[8..12], &ACTION_ANNOUNCE.to_be_bytes());
+        assert_eq!(&packet[16..36], &[7; 20]);
+        assert_eq!(&packet[36..56], &[9; 20]);
+
+        let mut answer = header(ACTION_ANNOUNCE, &packet[12..16]);
+        answer.extend_from_slice(&1800u32.to_be_bytes());
+        answer.extend_from_slice(&3u32.to_be_bytes());
+        answer.extend_from_slice(&5u32.to_be_bytes());
+        answer.extend_from_slice(peers);
+        server.send_to(&answer, from).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_and_announce_v4() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = tracker(&server).await;
+
+        let peers = [10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2];
+        let request = request();
+        let (response, _) = tokio::join!(tracker.announce(&request), announce_answer(&server, &peers));
+        let response = response.unwrap();
+
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!(response.incomplete, Some(3));
+        assert_eq!(response.complete, Some(5));
+        assert_eq!(response.peers, vec![
+            Peer::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 6881),
+            Peer::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6882),
+        ]);
+        assert_eq!(tracker.connection.map(|(connection_id, _)| connection_id), Some(CONNECTION_ID));
+    }
+
+    #[tokio::test]
+    async fn announce_v6() {
+        let server = UdpSocket::bind("[::1]:0").await.unwrap();
+        let mut tracker = tracker(&server).await;
+
+        let mut peers = Ipv6Addr::LOCALHOST.octets().to_vec();
+        peers.extend_from_slice(&6881u16.to_be_bytes());
+        let request = request();
+        let (response, _) = tokio::join!(tracker.announce(&request), announce_answer(&server, &peers));
+
+        assert_eq!(response.unwrap().peers, vec![Peer::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 6881)]);
+    }
+
+    #[tokio::test]
+    async fn scrape() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = tracker(&server).await;
+
+        let answer = async {
+            let (packet, from) = accept_connect(&server).await;
+            assert_eq!(&packet[8..12], &ACTION_SCRAPE.to_be_bytes());
+            assert_eq!(&packet[16..], [[1; 20], [2; 20]].concat().as_slice());
+
+            let mut answer = header(ACTION_SCRAPE, &packet[12..16]);
+            for value in [10u32, 20, 30, 11, 21, 31] {
+                answer.extend_from_slice(&value.to_be_bytes());
+            }
+            server.send_to(&answer, from).await.unwrap();
+        };
+        let (entries, _) = tokio::join!(tracker.scrape(&[[1; 20], [2; 20]]), answer);
+        let entries = entries.unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].complete, entries[0].downloaded, entries[0].incomplete), (10, 20, 30));
+        assert_eq!((entries[1].complete, entries[1].downloaded, entries[1].incomplete), (11, 21, 31));
+    }
+
+    #[tokio::test]
+    async fn error_action() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = tracker(&server).await;
+
+        let answer = async {
+            let (packet, from) = receive(&server).await;
+            let mut answer = header(ACTION_ERROR, &packet[12..16]);
+            answer.extend_from_slice(b"Torrent not registered");
+            server.send_to(&answer, from).await.unwrap();
+        };
+        let request = request();
+        let (response, _) = tokio::join!(tracker.announce(&request), answer);
+
+        assert!(matches!(response, Err(Error::TrackerFailure(message)) if message == "Torrent not registered"));
+    }
+
+    #[tokio::test]
+    async fn transaction_id_mismatch() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = tracker(&server).await;
+
+        let answer = async {
+            let (packet, from) = receive(&server).await;
+            let transaction_id = u32::from_be_bytes(packet[12..16].try_into().unwrap());
+
+            //A stale answer first, it must be ignored
+            let mut stale = header(ACTION_CONNECT, &transaction_id.wrapping_add(1).to_be_bytes());
+            stale.extend_from_slice(&0u64.to_be_bytes());
+            server.send_to(&stale, from).await.unwrap();
+
+            let mut answer = header(ACTION_CONNECT, &packet[12..16]);
+            answer.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+            server.send_to(&answer, from).await.unwrap();
+
+            let (packet, from) = receive(&server).await;
+            assert_eq!(&packet[0..8], &CONNECTION_ID.to_be_bytes());
+            let mut answer = header(ACTION_SCRAPE, &packet[12..16]);
+            answer.extend_from_slice(&[0; 12]);
+            server.send_to(&answer, from).await.unwrap();
+        };
+        let (entries, _) = tokio::join!(tracker.scrape(&[[1; 20]]), answer);
+
+        assert_eq!(entries.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retransmit() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = tracker(&server).await;
+
+        let answer = async {
+            //The first connect request is lost
+            let (lost, _) = receive(&server).await;
+            let (packet, from) = receive(&server).await;
+            assert_eq!(&packet[8..12], &ACTION_CONNECT.to_be_bytes());
+            assert_ne!(&lost[12..16], &packet[12..16]);
+
+            let mut answer = header(ACTION_CONNECT, &packet[12..16]);
+            answer.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+            server.send_to(&answer, from).await.unwrap();
+
+            let (packet, from) = receive(&server).await;
+            let mut answer = header(ACTION_SCRAPE, &packet[12..16]);
+            answer.extend_from_slice(&[0; 12]);
+            server.send_to(&answer, from).await.unwrap();
+        };
+        let (entries, _) = tokio::join!(tracker.scrape(&[[1; 20]]), answer);
+
+        assert_eq!(entries.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_are_counted_per_request() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = tracker(&server).await;
+
+        //Answers the second connect request, then never the announce
+        let answer = async {
+            let _ = receive(&server).await;
+            let _ = accept_connect(&server).await;
+            let mut packets = 1;
+            while time::timeout(Duration::from_millis(500), receive(&server)).await.is_ok() {
+                packets += 1;
+            }
+            packets
+        };
+        let request = request();
+        let (response, packets) = tokio::join!(tracker.announce(&request), answer);
+
+        assert!(matches!(response, Err(Error::Tracker(_))));
+        //The lost connect request used up the first of the three attempts
+        assert_eq!(packets, 2);
+    }
+}
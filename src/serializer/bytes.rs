@@ -1,12 +1,13 @@
-use std::net::{IpAddr, Ipv4Addr};
-use crate::shared::{PEER_SIZE, SyncResult};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::error::Error;
+use crate::shared::{PEER_SIZE, PEER_V6_SIZE, SyncResult};
 use crate::types::message::{Handshake, Message, MessageCode};
 use crate::types::peer::Peer;
 
 impl Message {
     pub fn to_bytes(&self) -> SyncResult<Vec<u8>> {
-        //Keep-alive message
-        if self.id == MessageCode::MessageKeepAlive || self.payload.is_empty() {
+        //Keep-alive message, other messages like unchoke or interested have an empty payload but still carry their id
+        if self.id == MessageCode::MessageKeepAlive {
             return Ok(vec![0; 4]);
         }
 
@@ -36,8 +37,8 @@ impl Handshake {
 
         buf.push(self.pstr.len() as u8);
         buf.extend(self.pstr.as_bytes());
-        buf.extend(vec![0; 8]);
-        buf.extend(hex::decode(&self.info_hash)?);
+        buf.extend(self.reserved);
+        buf.extend(hex::decode(&self.info_hash).map_err(|_| Error::MetaInfo("Info hash is not hex encoded".into()))?);
         buf.extend(&self.peer_id);
 
         Ok(buf)
@@ -45,7 +46,9 @@ impl Handshake {
 
     pub fn from_bytes(pstr_len: usize, buf: &[u8]) -> SyncResult<Handshake> {
         let buffer_end = pstr_len;
-        let pstr = std::str::from_utf8(&buf[0..buffer_end])?;
+        let pstr = std::str::from_utf8(&buf[0..buffer_end]).map_err(|_| Error::Protocol("Handshake pstr is not utf-8".into()))?;
+
+        let reserved = buf[pstr_len..pstr_len + 8].try_into().map_err(|_| Error::Protocol("Handshake is too short".into()))?;
 
         let buffer_start = pstr_len + 8;
         let buffer_end = pstr_len + 20 + 8;
@@ -53,10 +56,11 @@ impl Handshake {
 
         let buffer_start = pstr_len + 20 + 8;
         let buffer_end = pstr_len + 20 + 20 + 8;
-        let peer_id = buf[buffer_start..buffer_end].try_into()?;
+        let peer_id = buf[buffer_start..buffer_end].try_into().map_err(|_| Error::Protocol("Handshake is too short".into()))?;
 
         Ok(Handshake {
             pstr: pstr.to_string(),
+            reserved,
             info_hash,
             peer_id,
         })
@@ -66,8 +70,8 @@ impl Handshake {
 impl Peer {
     pub fn from_bytes(bytes: &[u8]) -> SyncResult<Vec<Peer>> {
         let peer_length = bytes.len();
-        if peer_length % PEER_SIZE as usize != 0 {
-            return Err("Peer length is not a multiple of 6".into());
+        if !peer_length.is_multiple_of(PEER_SIZE as usize) {
+            return Err(Error::Tracker("Peer length is not a multiple of 6".into()));
         }
 
         let mut peers = Vec::new();
@@ -86,6 +90,22 @@ impl Peer {
             index += PEER_SIZE as usize;
         }
 
+        Ok(peers)
+    }
+    pub fn from_bytes_v6(bytes: &[u8]) -> SyncResult<Vec<Peer>> {
+        if !bytes.len().is_multiple_of(PEER_V6_SIZE as usize) {
+            return Err(Error::Tracker("Peer length is not a multiple of 18".into()));
+        }
+
+        let mut peers = Vec::new();
+
+        for chunk in bytes.chunks_exact(PEER_V6_SIZE as usize) {
+            let octets: [u8; 16] = chunk[0..16].try_into().map_err(|_| Error::Tracker("Peer is too short".into()))?;
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+
+            peers.push(Peer::new(IpAddr::V6(Ipv6Addr::from(octets)), port));
+        }
+
         Ok(peers)
     }
 }
\ No newline at end of file
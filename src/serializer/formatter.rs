@@ -1,3 +1,4 @@
+use crate::types::bitfield::BitField;
 use crate::types::message::{Message, MessageCode};
 
 impl Message {
@@ -18,4 +19,45 @@ impl Message {
 
         Message::new(MessageCode::MessageHave, have)
     }
+
+    pub fn format_cancel(index: u32, begin: u32, length: u32) -> Message {
+        let mut cancel = Message::format_request(index, begin, length);
+        cancel.id = MessageCode::MessageCancel;
+
+        cancel
+    }
+
+    pub fn format_reject(index: u32, begin: u32, length: u32) -> Message {
+        let mut reject = Message::format_request(index, begin, length);
+        reject.id = MessageCode::MessageReject;
+
+        reject
+    }
+
+    pub fn format_allowed_fast(index: u32) -> Message {
+        Message::new(MessageCode::MessageAllowedFast, index.to_be_bytes().to_vec())
+    }
+
+    pub fn format_piece(index: u32, begin: u32, block: &[u8]) -> Message {
+        let mut piece = Vec::with_capacity(8 + block.len());
+
+        piece.extend_from_slice(&index.to_be_bytes());
+        piece.extend_from_slice(&begin.to_be_bytes());
+        piece.extend_from_slice(block);
+
+        Message::new(MessageCode::MessagePiece, piece)
+    }
+
+    pub fn format_bitfield(bitfield: &BitField) -> Message {
+        Message::new(MessageCode::MessageBitfield, bitfield.bits.clone())
+    }
+
+    pub fn format_extended(extension_id: u8, payload: &[u8]) -> Message {
+        let mut extended = Vec::with_capacity(1 + payload.len());
+
+        extended.push(extension_id);
+        extended.extend_from_slice(payload);
+
+        Message::new(MessageCode::MessageExtended, extended)
+    }
 }
\ No newline at end of file
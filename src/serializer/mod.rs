@@ -1,3 +1,4 @@
 pub mod bytes;
 pub mod formatter;
-pub mod parser;
\ No newline at end of file
+pub mod parser;
+pub mod scanner;
\ No newline at end of file
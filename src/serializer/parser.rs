@@ -1,86 +1,137 @@
 use crate::connection::client::Client;
+use crate::engine::context::PeerContext;
+use crate::error::Error;
 use crate::shared::SyncResult;
 use crate::types::message::{Message, MessageCode};
 use crate::types::piece::PieceProgress;
+use crate::utils::data::manipulator;
+
+fn payload_u32(payload: &[u8], offset: usize) -> SyncResult<u32> {
+    manipulator::read_u32(payload, offset).ok_or_else(|| Error::Protocol("Message payload is too short".into()))
+}
 
 impl Message {
-    pub fn parse_piece(&self, index: u32) -> SyncResult<Vec<u8>> {
+    pub fn parse_piece(&self) -> SyncResult<(u32, u32, Vec<u8>)> {
         let payload = self.payload.as_slice();
 
         if self.id != MessageCode::MessagePiece {
-            return Err("Message is not a piece".into());
+            return Err(Error::Protocol("Message is not a piece".into()));
         }
 
         if self.payload.len() < 8 {
-            return Err("Message payload is too short".into());
+            return Err(Error::Protocol("Message payload is too short".into()));
         }
 
-        let parsed_index = u32::from_ne_bytes(payload[0..4].try_into()?);
-        if parsed_index != index {
-            return Err("Piece index does not match".into());
-        }
+        let index = payload_u32(payload, 0)?;
+        let begin = payload_u32(payload, 4)?;
 
-        let begin = u32::from_ne_bytes(payload[4..8].try_into()?);
-        if begin >= self.payload.len() as u32 {
-            return Err("Begin is out of bounds".into());
-        }
-
-        Ok(self.payload[8..].to_vec())
+        Ok((index, begin, self.payload[8..].to_vec()))
     }
 
     pub fn parse_have(&self) -> SyncResult<u32> {
         let payload = self.payload.as_slice();
 
-        if self.id != MessageCode::MessageHave {
-            return Err("Message is not a have".into());
+        //Suggest and allowed fast carry a single piece index too
+        if !matches!(self.id, MessageCode::MessageHave | MessageCode::MessageSuggest | MessageCode::MessageAllowedFast) {
+            return Err(Error::Protocol("Message is not a have".into()));
         }
 
         if self.payload.len() != 4 {
-            return Err("Message payload is not 4 bytes".into());
+            return Err(Error::Protocol("Message payload is not 4 bytes".into()));
         }
 
-        let index = u32::from_ne_bytes(payload[0..4].try_into()?);
+        let index = payload_u32(payload, 0)?;
         Ok(index)
     }
+
+    pub fn parse_request(&self) -> SyncResult<(u32, u32, u32)> {
+        let payload = self.payload.as_slice();
+
+        if !matches!(self.id, MessageCode::MessageRequest | MessageCode::MessageCancel | MessageCode::MessageReject) {
+            return Err(Error::Protocol("Message is not a request".into()));
+        }
+
+        if self.payload.len() != 12 {
+            return Err(Error::Protocol("Message payload is not 12 bytes".into()));
+        }
+
+        let index = payload_u32(payload, 0)?;
+        let begin = payload_u32(payload, 4)?;
+        let length = payload_u32(payload, 8)?;
+
+        Ok((index, begin, length))
+    }
+
+    pub fn parse_extended(&self) -> SyncResult<(u8, &[u8])> {
+        if self.id != MessageCode::MessageExtended {
+            return Err(Error::Protocol("Message is not an extended message".into()));
+        }
+
+        if self.payload.is_empty() {
+            return Err(Error::Protocol("Message payload is empty".into()));
+        }
+
+        Ok((self.payload[0], &self.payload[1..]))
+    }
 }
 
 impl PieceProgress {
-    pub async fn parse_message(&mut self, client: &mut Client) -> SyncResult<()> {
+    pub async fn parse_message(&mut self, client: &mut Client, context: &PeerContext) -> SyncResult<()> {
         let message = client.read_message().await?;
         if message.id == MessageCode::MessageKeepAlive {
             return Ok(());
         }
 
         match message.id {
-            MessageCode::MessageUnchoke => {
-                println!("[PieceProgress - parse_message] Received unchoke");
-                client.choked = false
-            },
+            MessageCode::MessageUnchoke => client.choked = false,
             MessageCode::MessageChoke => {
-                println!("[PieceProgress - parse_message] Received choke");
-                client.choked = true
+                client.choked = true;
+
+                //Without the fast extension a choke silently drops every pending request
+                if !client.supports_fast() {
+                    self.requeue_all();
+                }
+            },
+            MessageCode::MessageReject => {
+                let (index, begin, _length) = message.parse_request()?;
+
+                if index == self.index {
+                    self.requeue_block(begin);
+                }
             },
             MessageCode::MessageHave => {
                 let index = message.parse_have()?;
 
-                println!("[PieceProgress - parse_message] Received have for piece {}", index);
-                client.bitfield.set_piece(index);
+                client.receive_have(index, context)?;
+                client.update_interest(context).await?;
             },
             MessageCode::MessagePiece => {
-                println!("[PieceProgress - parse_message] Received piece message");
-                let data = message.parse_piece(self.index)?;
+                let (index, begin, data) = message.parse_piece()?;
+                //Blocks of a piece we gave up on or cancelled may still be on their way
+                if index != self.index {
+                    return Ok(());
+                }
+
+                let position = match self.pending.iter().position(|(pending, length)| *pending == begin && *length as usize == data.len()) {
+                    Some(position) => position,
+                    None => return Ok(()),
+                };
+                self.pending.remove(position);
+
+                let end = begin as usize + data.len();
+                if end > self.data.len() {
+                    return Err(Error::Protocol("Block is out of the piece bounds".into()));
+                }
+
                 let length = data.len() as u32;
-                self.data.extend(data);
+                self.data[begin as usize..end].copy_from_slice(&data);
 
                 self.downloaded += length;
                 self.backlog -= 1;
-
-                println!("[PieceProgress - parse_message] [Piece {}] Downloaded: {}", self.index, self.downloaded);
-                println!("[PieceProgress - parse_message] [Piece {}] Backlog: {}", self.index, self.backlog);
+                self.received.push(begin);
+                client.slot.add_downloaded(length as u64);
             },
-            _ => {
-                println!("[PieceProgress - parse_message] Received unexpected message: {}", <MessageCode as Into<u8>>::into(message.id));
-            }
+            _ => client.serve_message(&message, context).await?,
         }
 
         Ok(())
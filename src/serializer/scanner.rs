@@ -0,0 +1,150 @@
+use crate::error::Error;
+use crate::shared::SyncResult;
+
+//Lists and dictionaries nested deeper than this are rejected, the data comes from peers and must not overflow the stack
+const MAX_DEPTH: usize = 64;
+
+//Returns the index right after the bencoded value starting at `start`
+pub fn skip_value(raw: &[u8], start: usize) -> SyncResult<usize> {
+    skip_nested(raw, start, 0)
+}
+
+fn skip_nested(raw: &[u8], start: usize, depth: usize) -> SyncResult<usize> {
+    let first = *raw.get(start).ok_or_else(|| invalid("Unexpected end of bencoded data"))?;
+
+    match first {
+        b'i' => {
+            let end = find_byte(raw, start + 1, b'e')?;
+            Ok(end + 1)
+        },
+        b'l' | b'd' => {
+            if depth >= MAX_DEPTH {
+                return Err(Error::Protocol("Bencoded data is nested too deeply".into()));
+            }
+
+            let mut position = start + 1;
+
+            while *raw.get(position).ok_or_else(|| invalid("Unexpected end of bencoded data"))? != b'e' {
+                position = skip_nested(raw, position, depth + 1)?;
+            }
+
+            Ok(position + 1)
+        },
+        b'0'..=b'9' => {
+            let (content_start, length) = read_string_header(raw, start)?;
+            let end = content_start.checked_add(length).ok_or_else(|| invalid("Bencoded string length overflows"))?;
+
+            if end > raw.len() {
+                return Err(invalid("Bencoded string is longer than the data"));
+            }
+
+            Ok(end)
+        },
+        _ => Err(invalid("Invalid bencoded value")),
+    }
+}
+
+//Returns the exact bytes of the value stored under `key` in the top-level dictionary
+pub fn find_dictionary_value<'a>(raw: &'a [u8], key: &[u8]) -> SyncResult<&'a [u8]> {
+    if raw.first() != Some(&b'd') {
+        return Err(invalid("Bencoded data is not a dictionary"));
+    }
+
+    let mut position = 1;
+    while *raw.get(position).ok_or_else(|| invalid("Unexpected end of bencoded data"))? != b'e' {
+        let (key_start, key_length) = read_string_header(raw, position)?;
+        let value_start = skip_value(raw, position)?;
+        let value_end = skip_nested(raw, value_start, 1)?;
+
+        if raw[key_start..key_start + key_length] == *key {
+            return Ok(&raw[value_start..value_end]);
+        }
+
+        position = value_end;
+    }
+
+    Err(invalid("Key not found in bencoded dictionary"))
+}
+
+fn read_string_header(raw: &[u8], start: usize) -> SyncResult<(usize, usize)> {
+    let colon = find_byte(raw, start, b':')?;
+    let length = std::str::from_utf8(&raw[start..colon]).ok()
+        .and_then(|length| length.parse::<usize>().ok())
+        .ok_or_else(|| invalid("Invalid bencoded string length"))?;
+
+    Ok((colon + 1, length))
+}
+
+fn find_byte(raw: &[u8], start: usize, byte: u8) -> SyncResult<usize> {
+    raw.iter()
+        .skip(start)
+        .position(|current| *current == byte)
+        .map(|offset| start + offset)
+        .ok_or_else(|| invalid("Unexpected end of bencoded data"))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::Bencode(serde_bencode::Error::Custom(message.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_nested_values() {
+        let raw = b"d4:infod5:filesld6:lengthi5eeee4:name3:abce";
+
+        assert_eq!(skip_value(raw, 0).unwrap(), raw.len());
+        assert_eq!(skip_value(raw, 1).unwrap(), 7);
+        assert_eq!(skip_value(raw, 7).unwrap(), 31);
+        assert_eq!(skip_value(raw, 15).unwrap(), 30);
+    }
+
+    #[test]
+    fn finds_nested_dictionary() {
+        let raw = b"d8:announce3:url4:infod6:lengthi5e4:name3:abce5:otheri1ee";
+
+        assert_eq!(find_dictionary_value(raw, b"info").unwrap(), b"d6:lengthi5e4:name3:abce");
+        assert_eq!(find_dictionary_value(raw, b"other").unwrap(), b"i1e");
+    }
+
+    #[test]
+    fn missing_key() {
+        let raw = b"d8:announce3:url4:infod4:name3:abcee";
+
+        //Keys of nested dictionaries are not top-level keys
+        assert!(find_dictionary_value(raw, b"name").is_err());
+        assert!(find_dictionary_value(raw, b"missing").is_err());
+        assert!(find_dictionary_value(b"l4:infoe", b"info").is_err());
+    }
+
+    #[test]
+    fn truncated_input() {
+        let raw = b"d4:infod6:lengthi5e4:name3:abcee";
+
+        //The value is returned as soon as it is found, only the outer end may be missing
+        for end in 0..raw.len() - 1 {
+            assert!(skip_value(&raw[..end], 0).is_err());
+            assert!(find_dictionary_value(&raw[..end], b"info").is_err());
+        }
+
+        assert!(skip_value(b"10:abc", 0).is_err());
+        assert!(skip_value(b"i12", 0).is_err());
+        assert!(skip_value(b"18446744073709551615:a", 0).is_err());
+    }
+
+    #[test]
+    fn depth_limit() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+
+        assert_eq!(skip_value(&nested(MAX_DEPTH), 0).unwrap(), MAX_DEPTH * 2);
+        assert!(matches!(skip_value(&nested(MAX_DEPTH + 1), 0), Err(Error::Protocol(_))));
+        assert!(matches!(skip_value(&nested(100_000), 0), Err(Error::Protocol(_))));
+
+        let mut raw = b"d4:info".to_vec();
+        raw.extend(nested(MAX_DEPTH));
+        raw.push(b'e');
+        assert!(matches!(find_dictionary_value(&raw, b"info"), Err(Error::Protocol(_))));
+    }
+}
@@ -0,0 +1,290 @@
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use crate::error::Error;
+use crate::shared::{SizedBytes, SyncResult};
+
+//Largest block the peer protocol lets us ask for, most clients drop peers requesting more
+const MAX_REQUEST_SIZE: u32 = 131072;
+
+//Tunables of a session, every field missing from a settings file keeps its default
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    //Read when the session binds its listener and generates its peer id, they cannot be changed afterwards
+    pub listen_port: u16,
+    //Azureus-style client tag, the rest of the 20 bytes peer id is random
+    pub peer_id_prefix: String,
+    //Sent to HTTP trackers
+    pub user_agent: String,
+    //Peers asked from trackers on every announce
+    pub numwant: i32,
+
+    //Connections kept per torrent, and connection attempts in flight at once
+    pub max_connections: usize,
+    pub max_half_open: usize,
+    //Peers we upload to at once, the optimistic unchoke included
+    pub upload_slots: usize,
+    //Pieces a peer may send with a bad hash before it is dropped
+    pub max_hash_failures: u32,
+    //Pieces a choked peer may still request, from the fast extension
+    pub allowed_fast_count: usize,
+
+    //Size of the blocks we request, and of the largest block we serve
+    pub block_size: u32,
+    pub max_request_size: u32,
+    //Requests kept in flight to a single peer
+    pub request_queue_depth: u32,
+
+    //Timeouts, in seconds
+    pub connect_timeout: u64,
+    pub handshake_timeout: u64,
+    pub piece_timeout: u64,
+    pub tracker_timeout: u64,
+
+    //Bytes of recently served pieces kept in memory, 0 reads every block from the storage
+    pub read_cache_size: u64,
+}
+
+//Settings shared by every part of a session, changes are picked up the next time a value is read
+#[derive(Debug, Default)]
+pub struct SharedSettings {
+    current: RwLock<Arc<Settings>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            listen_port: 6881,
+            peer_id_prefix: "-RS0001-".to_string(),
+            user_agent: concat!("bit-torrent-rs/", env!("CARGO_PKG_VERSION")).to_string(),
+            numwant: 200,
+
+            max_connections: 50,
+            max_half_open: 8,
+            upload_slots: 4,
+            max_hash_failures: 3,
+            allowed_fast_count: 10,
+
+            block_size: 16384,
+            max_request_size: 16384,
+            request_queue_depth: 5,
+
+            connect_timeout: 10,
+            handshake_timeout: 10,
+            piece_timeout: 30,
+            tracker_timeout: 30,
+
+            read_cache_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_toml(text: &str) -> SyncResult<Self> {
+        let settings = toml::from_str::<Settings>(text).map_err(|error| Error::Settings(error.to_string()))?;
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    pub fn from_json(text: &str) -> SyncResult<Self> {
+        let settings = serde_json::from_str::<Settings>(text).map_err(|error| Error::Settings(error.to_string()))?;
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    //The format is picked from the extension, anything but .json is read as TOML
+    pub fn load(path: &Path) -> SyncResult<Self> {
+        let text = std::fs::read_to_string(path).map_err(Error::Disk)?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Settings::from_json(&text),
+            _ => Settings::from_toml(&text),
+        }
+    }
+
+    pub fn to_toml(&self) -> SyncResult<String> {
+        toml::to_string_pretty(self).map_err(|error| Error::Settings(error.to_string()))
+    }
+
+    pub fn to_json(&self) -> SyncResult<String> {
+        serde_json::to_string_pretty(self).map_err(|error| Error::Settings(error.to_string()))
+    }
+
+    //Rejects the values the engine cannot work with
+    pub fn validate(&self) -> SyncResult<()> {
+        if self.peer_id_prefix.len() > 20 {
+            return Err(Error::Settings("Peer id prefix is longer than 20 bytes".into()));
+        }
+
+        if self.block_size == 0 || self.block_size > MAX_REQUEST_SIZE {
+            return Err(Error::Settings(format!("Block size must be between 1 and {} bytes", MAX_REQUEST_SIZE)));
+        }
+
+        if self.max_request_size < self.block_size || self.max_request_size > MAX_REQUEST_SIZE {
+            return Err(Error::Settings(format!("Max request size must be between the block size and {} bytes", MAX_REQUEST_SIZE)));
+        }
+
+        if self.request_queue_depth == 0 || self.max_connections == 0 || self.max_half_open == 0 || self.upload_slots == 0 || self.max_hash_failures == 0 {
+            return Err(Error::Settings("Limits must be at least 1".into()));
+        }
+
+        Ok(())
+    }
+
+    pub fn generate_peer_id(&self) -> SizedBytes {
+        let mut peer_id = [0; 20];
+        let prefix = self.peer_id_prefix.as_bytes();
+        let length = prefix.len().min(peer_id.len());
+        peer_id[..length].copy_from_slice(&prefix[..length]);
+
+        let mut rng = rand::thread_rng();
+        for byte in peer_id[length..].iter_mut() {
+            *byte = rng.sample(Alphanumeric);
+        }
+
+        peer_id
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout)
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout)
+    }
+
+    pub fn piece_timeout(&self) -> Duration {
+        Duration::from_secs(self.piece_timeout)
+    }
+
+    pub fn tracker_timeout(&self) -> Duration {
+        Duration::from_secs(self.tracker_timeout)
+    }
+}
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> SyncResult<Self> {
+        settings.validate()?;
+
+        Ok(Self {
+            current: RwLock::new(Arc::new(settings)),
+        })
+    }
+
+    //A snapshot, it does not change when the settings are replaced
+    pub fn get(&self) -> Arc<Settings> {
+        //Settings are swapped whole, a panic while holding the lock cannot leave them half written
+        self.current.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    //Rejects changes to the settings only read at startup, they would be silently ignored
+    pub fn set(&self, settings: Settings) -> SyncResult<()> {
+        settings.validate()?;
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+
+        if settings.listen_port != current.listen_port || settings.peer_id_prefix != current.peer_id_prefix {
+            return Err(Error::Settings("Listen port and peer id prefix can only be set when the session starts".into()));
+        }
+        *current = Arc::new(settings);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_toml_and_json() {
+        let settings = Settings::from_toml("listen_port = 7000\nmax_connections = 20\nuser_agent = \"test\"").unwrap();
+        assert_eq!(settings.listen_port, 7000);
+        assert_eq!(settings.max_connections, 20);
+        assert_eq!(settings.user_agent, "test");
+        //Missing fields keep their default
+        assert_eq!(settings.block_size, Settings::default().block_size);
+
+        let settings = Settings::from_json(r#"{"upload_slots": 8, "piece_timeout": 60}"#).unwrap();
+        assert_eq!(settings.upload_slots, 8);
+        assert_eq!(settings.piece_timeout(), Duration::from_secs(60));
+        assert_eq!(settings.listen_port, Settings::default().listen_port);
+
+        //Both formats read back what they write
+        let settings = Settings { numwant: 50, read_cache_size: 0, ..Settings::default() };
+        assert_eq!(Settings::from_toml(&settings.to_toml().unwrap()).unwrap(), settings);
+        assert_eq!(Settings::from_json(&settings.to_json().unwrap()).unwrap(), settings);
+    }
+
+    #[test]
+    fn loads_files_by_extension() {
+        let directory = std::env::temp_dir().join(format!("settings-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        std::fs::write(directory.join("settings.json"), r#"{"numwant": 10}"#).unwrap();
+        std::fs::write(directory.join("settings.conf"), "numwant = 20").unwrap();
+        assert_eq!(Settings::load(&directory.join("settings.json")).unwrap().numwant, 10);
+        assert_eq!(Settings::load(&directory.join("settings.conf")).unwrap().numwant, 20);
+        assert!(matches!(Settings::load(&directory.join("missing.toml")), Err(Error::Disk(_))));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_fields_and_bad_values() {
+        assert!(matches!(Settings::from_toml("listen_prot = 7000"), Err(Error::Settings(_))));
+        assert!(matches!(Settings::from_json(r#"{"max_conections": 5}"#), Err(Error::Settings(_))));
+        assert!(matches!(Settings::from_toml("listen_port = \"7000\""), Err(Error::Settings(_))));
+        assert!(matches!(Settings::from_json("{"), Err(Error::Settings(_))));
+
+        let invalid = [
+            "peer_id_prefix = \"-ABCDEFGHIJKLMNOPQRST-\"",
+            "block_size = 0",
+            "block_size = 262144",
+            "block_size = 32768\nmax_request_size = 16384",
+            "max_request_size = 262144",
+            "request_queue_depth = 0",
+            "max_connections = 0",
+            "max_half_open = 0",
+            "upload_slots = 0",
+            "max_hash_failures = 0",
+        ];
+        for text in invalid {
+            assert!(matches!(Settings::from_toml(text), Err(Error::Settings(_))), "{}", text);
+        }
+
+        assert!(Settings::from_toml("block_size = 32768\nmax_request_size = 65536").is_ok());
+    }
+
+    #[test]
+    fn peer_ids_start_with_the_prefix() {
+        let settings = Settings { peer_id_prefix: "-XY1234-".into(), ..Settings::default() };
+
+        let peer_id = settings.generate_peer_id();
+        assert!(peer_id.starts_with(b"-XY1234-"));
+        assert!(peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(settings.generate_peer_id(), peer_id);
+    }
+
+    #[test]
+    fn shared_settings_reject_startup_only_changes() {
+        let shared = SharedSettings::new(Settings::default()).unwrap();
+        let before = shared.get();
+
+        shared.set(Settings { upload_slots: 10, ..Settings::default() }).unwrap();
+        assert_eq!(shared.get().upload_slots, 10);
+        //Snapshots taken before keep their values
+        assert_eq!(before.upload_slots, Settings::default().upload_slots);
+
+        assert!(matches!(shared.set(Settings { listen_port: 7000, ..Settings::default() }), Err(Error::Settings(_))));
+        assert!(matches!(shared.set(Settings { peer_id_prefix: "-XY1234-".into(), ..Settings::default() }), Err(Error::Settings(_))));
+        assert!(matches!(shared.set(Settings { block_size: 0, ..Settings::default() }), Err(Error::Settings(_))));
+        //Rejected settings leave the current ones in place
+        assert_eq!(shared.get().upload_slots, 10);
+    }
+}
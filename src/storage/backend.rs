@@ -0,0 +1,26 @@
+use crate::shared::SyncResult;
+use crate::storage::layout::StorageLayout;
+use crate::types::piece::PieceWork;
+
+//Where the data of a torrent lives, blocks are addressed by piece like in the peer protocol
+pub trait Storage: Send + Sync {
+    fn layout(&self) -> &StorageLayout;
+
+    fn read_block(&self, piece_index: u32, begin: u32, length: u32) -> SyncResult<Vec<u8>>;
+
+    fn write_block(&self, piece_index: u32, begin: u32, data: &[u8]) -> SyncResult<()>;
+
+    //Makes everything written so far durable
+    fn flush(&self) -> SyncResult<()>;
+
+    fn write_piece(&self, piece_index: u32, data: &[u8]) -> SyncResult<()> {
+        self.write_block(piece_index, 0, data)
+    }
+
+    //An error means the piece could not be read at all, not that it is corrupt
+    fn verify_piece(&self, piece: &PieceWork) -> SyncResult<bool> {
+        let data = self.read_block(piece.index, 0, piece.length)?;
+
+        Ok(piece.check_integrity(&data))
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::storage::backend::Storage;
+use crate::utils::data::calculator;
+
+//Whole pieces read to serve a block, peers usually request the rest of the piece right after
+#[derive(Default)]
+pub struct PieceCache {
+    //Least recently used first
+    pieces: Mutex<VecDeque<(u32, Arc<Vec<u8>>)>>,
+}
+
+impl PieceCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //The capacity is in bytes, pieces larger than it are never cached
+    pub fn read_block(&self, storage: &dyn Storage, piece_index: u32, begin: u32, length: u32, capacity: u64) -> SyncResult<Vec<u8>> {
+        let layout = storage.layout();
+        let piece_length = calculator::calculate_piece_size(layout.length, layout.piece_length, piece_index);
+        if (piece_length as u64) > capacity {
+            return storage.read_block(piece_index, begin, length);
+        }
+
+        let piece = match self.get(piece_index)? {
+            Some(piece) => piece,
+            None => {
+                let piece = Arc::new(storage.read_block(piece_index, 0, piece_length)?);
+                self.insert(piece_index, piece.clone(), capacity)?;
+                piece
+            }
+        };
+
+        piece.get(begin as usize..begin as usize + length as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| Error::Storage("Block is out of the piece bounds".into()))
+    }
+
+    //Needed once the data on disk may have changed under us
+    pub fn clear(&self) -> SyncResult<()> {
+        self.pieces.lock().map_err(|_| Error::Poisoned("Piece cache"))?.clear();
+
+        Ok(())
+    }
+
+    fn get(&self, piece_index: u32) -> SyncResult<Option<Arc<Vec<u8>>>> {
+        let mut pieces = self.pieces.lock().map_err(|_| Error::Poisoned("Piece cache"))?;
+
+        let position = pieces.iter().position(|(index, _)| *index == piece_index);
+        let entry = match position.and_then(|position| pieces.remove(position)) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        //Moved to the back, it was just used
+        let piece = entry.1.clone();
+        pieces.push_back(entry);
+
+        Ok(Some(piece))
+    }
+
+    fn insert(&self, piece_index: u32, piece: Arc<Vec<u8>>, capacity: u64) -> SyncResult<()> {
+        let mut pieces = self.pieces.lock().map_err(|_| Error::Poisoned("Piece cache"))?;
+
+        //Two connections may have read the same piece at once
+        if !pieces.iter().any(|(index, _)| *index == piece_index) {
+            pieces.push_back((piece_index, piece));
+        }
+
+        //The capacity may have shrunk since the last insert
+        let mut size = pieces.iter().map(|(_, piece)| piece.len() as u64).sum::<u64>();
+        while size > capacity {
+            match pieces.pop_front() {
+                Some((_, evicted)) => size -= evicted.len() as u64,
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::storage::backend::Storage;
+use crate::storage::layout::{FileSpan, StorageLayout};
+
+pub struct FileStorage {
+    pub layout: StorageLayout,
+    //Missing files of a read only storage have no handle
+    handles: Vec<Mutex<Option<File>>>,
+}
+
+impl FileStorage {
+    pub fn create(layout: StorageLayout) -> SyncResult<Self> {
+        let mut handles = Vec::with_capacity(layout.files.len());
+
+        for file in layout.files.iter() {
+            let parent = file.path.parent().ok_or_else(|| Error::Storage("Missing parent directory".into()))?;
+            std::fs::create_dir_all(parent).map_err(Error::Disk)?;
+
+            //Existing data is kept, the resume data tells which pieces of it are usable
+            let handle = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)
+                .map_err(Error::Disk)?;
+            if handle.metadata().map_err(Error::Disk)?.len() != file.length {
+                handle.set_len(file.length).map_err(Error::Disk)?;
+            }
+
+            handles.push(Mutex::new(Some(handle)));
+        }
+
+        Ok(Self {
+            layout,
+            handles,
+        })
+    }
+
+    //Opens the files that exist for reading only, nothing is created or resized
+    pub fn open(layout: StorageLayout) -> Self {
+        let handles = layout.files.iter()
+            .map(|file| Mutex::new(File::open(&file.path).ok()))
+            .collect();
+
+        Self {
+            layout,
+            handles,
+        }
+    }
+
+    fn write_spans(&self, spans: &[FileSpan], data: &[u8]) -> SyncResult<()> {
+        let mut position = 0;
+
+        for span in spans {
+            let mut handle = self.handles[span.file_index].lock().map_err(|_| Error::Poisoned("Storage file"))?;
+            let handle = handle.as_mut().ok_or_else(|| Error::Storage("Storage file is not open".into()))?;
+            let end = position + span.length as usize;
+
+            handle.seek(SeekFrom::Start(span.offset)).map_err(Error::Disk)?;
+            handle.write_all(&data[position..end]).map_err(Error::Disk)?;
+            position = end;
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+
+    fn read_block(&self, piece_index: u32, begin: u32, length: u32) -> SyncResult<Vec<u8>> {
+        let spans = self.layout.map_block(piece_index, begin, length)?;
+        let mut data = vec![0; length as usize];
+
+        let mut position = 0;
+        for span in spans {
+            let mut handle = self.handles[span.file_index].lock().map_err(|_| Error::Poisoned("Storage file"))?;
+            let handle = handle.as_mut().ok_or_else(|| Error::Storage("Storage file is not open".into()))?;
+            let end = position + span.length as usize;
+
+            handle.seek(SeekFrom::Start(span.offset)).map_err(Error::Disk)?;
+            handle.read_exact(&mut data[position..end]).map_err(Error::Disk)?;
+            position = end;
+        }
+
+        Ok(data)
+    }
+
+    fn write_block(&self, piece_index: u32, begin: u32, data: &[u8]) -> SyncResult<()> {
+        let spans = self.layout.map_block(piece_index, begin, data.len() as u32)?;
+
+        self.write_spans(&spans, data)
+    }
+
+    fn flush(&self) -> SyncResult<()> {
+        for handle in self.handles.iter() {
+            let handle = handle.lock().map_err(|_| Error::Poisoned("Storage file"))?;
+            if let Some(handle) = handle.as_ref() {
+                handle.sync_data().map_err(Error::Disk)?;
+            }
+        }
+
+        Ok(())
+    }
+}
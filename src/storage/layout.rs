@@ -0,0 +1,265 @@
+use std::path::{Component, Path, PathBuf};
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::types::bencode::MetaInfoFile;
+use crate::utils::data::{calculator, manipulator};
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    //Offset of the first byte of this file in the torrent byte space
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSpan {
+    pub file_index: usize,
+    //Offset inside the file, not inside the torrent
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageLayout {
+    pub files: Vec<FileEntry>,
+    pub piece_length: u32,
+    pub length: u64,
+}
+
+impl StorageLayout {
+    pub fn new(files: Vec<(PathBuf, u64)>, piece_length: u32) -> Self {
+        let mut entries = Vec::with_capacity(files.len());
+        let mut offset = 0;
+
+        for (path, length) in files {
+            entries.push(FileEntry { path, offset, length });
+            offset += length;
+        }
+
+        Self {
+            files: entries,
+            piece_length,
+            length: offset,
+        }
+    }
+
+    pub fn from_meta_info(meta_info: &MetaInfoFile, destination: PathBuf) -> SyncResult<Self> {
+        let mut files = Vec::new();
+
+        if meta_info.is_single_file_mode() {
+            let length = meta_info.info.length.ok_or_else(|| Error::MetaInfo("Missing length in .torrent file".into()))?;
+            let mut path = destination.clone();
+            StorageLayout::push_component(&mut path, &meta_info.info.name)?;
+            files.push((path, length));
+        }
+
+        if meta_info.is_multi_file_mode() {
+            let entries = meta_info.info.files.as_ref().ok_or_else(|| Error::MetaInfo("Missing files in .torrent file".into()))?;
+
+            for entry in entries {
+                let length = entry.length.ok_or_else(|| Error::MetaInfo("Missing length in .torrent file".into()))?;
+                let path_vec = entry.path.as_ref().ok_or_else(|| Error::MetaInfo("Missing path in .torrent file".into()))?;
+
+                if path_vec.is_empty() {
+                    return Err(Error::InvalidTorrent("File with an empty path".into()));
+                }
+
+                let mut path = destination.clone();
+                for component in path_vec {
+                    StorageLayout::push_component(&mut path, component)?;
+                }
+                files.push((path, length));
+            }
+        }
+
+        if files.is_empty() {
+            return Err(Error::MetaInfo("Torrent has neither length nor files".into()));
+        }
+
+        let layout = Self::new(files, meta_info.info.piece_length);
+        layout.check_piece_count(manipulator::split_piece_bytes(meta_info)?.len())?;
+
+        Ok(layout)
+    }
+
+    //Every piece but the last one is full, a torrent with more or fewer hashes than its length needs cannot be mapped
+    pub fn check_piece_count(&self, piece_count: usize) -> SyncResult<()> {
+        if self.piece_length == 0 {
+            return Err(Error::InvalidTorrent("Piece length is zero".into()));
+        }
+
+        let expected = self.length.div_ceil(self.piece_length as u64);
+        if piece_count as u64 != expected {
+            return Err(Error::InvalidTorrent(format!("Torrent has {} pieces but its length needs {}", piece_count, expected)));
+        }
+
+        Ok(())
+    }
+
+    //Path components come from the torrent, anything but a plain name could point outside of the destination
+    fn push_component(path: &mut PathBuf, component: &str) -> SyncResult<()> {
+        let mut components = Path::new(component).components();
+
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => {
+                path.push(name);
+                Ok(())
+            },
+            _ => Err(Error::InvalidTorrent(format!("Unsafe path component {:?}", component))),
+        }
+    }
+
+    pub fn piece_offset(&self, piece_index: u32) -> u64 {
+        let (begin, _end) = calculator::calculate_bounds_for_piece(self.length, self.piece_length, piece_index);
+
+        begin
+    }
+
+    //Maps a range of the torrent byte space onto the files it covers, in order
+    pub fn map_range(&self, offset: u64, length: u64) -> SyncResult<Vec<FileSpan>> {
+        if offset + length > self.length {
+            return Err(Error::Storage("Range is out of the torrent bounds".into()));
+        }
+
+        let mut spans = Vec::new();
+        let end = offset + length;
+
+        //Files are sorted by offset, so we can skip everything ending before the range
+        let first = self.files.partition_point(|file| file.offset + file.length <= offset);
+
+        for (file_index, file) in self.files.iter().enumerate().skip(first) {
+            if file.offset >= end {
+                break;
+            }
+
+            let begin = offset.max(file.offset);
+            let stop = end.min(file.offset + file.length);
+            if begin == stop {
+                //Zero-length files do not hold any byte of the range
+                continue;
+            }
+
+            spans.push(FileSpan {
+                file_index,
+                offset: begin - file.offset,
+                length: stop - begin,
+            });
+        }
+
+        Ok(spans)
+    }
+
+    pub fn map_block(&self, piece_index: u32, begin: u32, length: u32) -> SyncResult<Vec<FileSpan>> {
+        if begin as u64 + length as u64 > self.piece_length as u64 {
+            return Err(Error::Storage("Block is out of the piece bounds".into()));
+        }
+
+        self.map_range(self.piece_offset(piece_index) + begin as u64, length as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+    use crate::types::bencode::{MetaInfoDictionary, MetaInfoFileEntry};
+    use super::*;
+
+    fn meta_info(name: &str, files: &[(&[&str], u64)], piece_length: u32, pieces: usize) -> MetaInfoFile {
+        let entries = files.iter().map(|(path, length)| MetaInfoFileEntry {
+            length: Some(*length),
+            path: Some(path.iter().map(|component| component.to_string()).collect()),
+            md5sum: None,
+        }).collect();
+
+        MetaInfoFile {
+            info: MetaInfoDictionary {
+                name: name.to_string(),
+                piece_length,
+                pieces: ByteBuf::from(vec![0; 20 * pieces]),
+                private: None,
+                length: None,
+                md5sum: None,
+                files: Some(entries),
+            },
+            announce: String::new(),
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            info_bytes: ByteBuf::new(),
+        }
+    }
+
+    fn single_file(name: &str, length: u64, piece_length: u32, pieces: usize) -> MetaInfoFile {
+        let mut meta_info = meta_info(name, &[], piece_length, pieces);
+        meta_info.info.files = None;
+        meta_info.info.length = Some(length);
+
+        meta_info
+    }
+
+    fn is_invalid(result: SyncResult<StorageLayout>) -> bool {
+        matches!(result, Err(Error::InvalidTorrent(_)))
+    }
+
+    #[test]
+    fn files_stay_inside_the_destination() {
+        let destination = PathBuf::from("downloads");
+        let layout = StorageLayout::from_meta_info(&meta_info("dir", &[(&["a", "b.txt"], 10), (&["c.txt"], 6)], 16, 1), destination.clone()).unwrap();
+
+        let paths = layout.files.iter().map(|file| file.path.clone()).collect::<Vec<_>>();
+        assert_eq!(paths, vec![destination.join("a").join("b.txt"), destination.join("c.txt")]);
+        assert_eq!(layout.length, 16);
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        let destination = PathBuf::from("downloads");
+        let unsafe_paths: [&[&str]; 7] = [&[".."], &["a", "..", "..", "b"], &["/etc/passwd"], &["a/../../b"], &["."], &[""], &[]];
+
+        for path in unsafe_paths {
+            assert!(is_invalid(StorageLayout::from_meta_info(&meta_info("dir", &[(path, 16)], 16, 1), destination.clone())), "{:?}", path);
+        }
+
+        //The name of a single file torrent is a path component too
+        for name in ["..", "/tmp/x", "a/b", ""] {
+            assert!(is_invalid(StorageLayout::from_meta_info(&single_file(name, 16, 16, 1), destination.clone())), "{:?}", name);
+        }
+        assert!(StorageLayout::from_meta_info(&single_file("..a", 16, 16, 1), destination).is_ok());
+    }
+
+    #[test]
+    fn maps_ranges_across_file_boundaries() {
+        let layout = StorageLayout::new(vec![(PathBuf::from("a"), 10), (PathBuf::from("empty"), 0), (PathBuf::from("b"), 20), (PathBuf::from("c"), 5)], 16);
+
+        assert_eq!(layout.map_range(0, 10).unwrap(), vec![FileSpan { file_index: 0, offset: 0, length: 10 }]);
+        //Zero-length files never show up
+        assert_eq!(layout.map_range(8, 25).unwrap(), vec![
+            FileSpan { file_index: 0, offset: 8, length: 2 },
+            FileSpan { file_index: 2, offset: 0, length: 20 },
+            FileSpan { file_index: 3, offset: 0, length: 3 },
+        ]);
+        assert_eq!(layout.map_range(35, 0).unwrap(), Vec::new());
+        assert!(layout.map_range(30, 6).is_err());
+
+        //The second piece starts 6 bytes into the second file, the last one is shorter
+        assert_eq!(layout.map_block(1, 0, 16).unwrap(), vec![FileSpan { file_index: 2, offset: 6, length: 14 }, FileSpan { file_index: 3, offset: 0, length: 2 }]);
+        assert_eq!(layout.map_block(2, 0, 3).unwrap(), vec![FileSpan { file_index: 3, offset: 2, length: 3 }]);
+        assert!(layout.map_block(2, 0, 4).is_err());
+        assert!(layout.map_block(0, 8, 9).is_err());
+    }
+
+    #[test]
+    fn piece_count_must_match_the_length() {
+        let destination = PathBuf::from("downloads");
+
+        assert!(StorageLayout::from_meta_info(&single_file("a", 32, 16, 2), destination.clone()).is_ok());
+        assert!(StorageLayout::from_meta_info(&single_file("a", 33, 16, 3), destination.clone()).is_ok());
+        assert!(is_invalid(StorageLayout::from_meta_info(&single_file("a", 32, 16, 3), destination.clone())));
+        assert!(is_invalid(StorageLayout::from_meta_info(&single_file("a", 33, 16, 2), destination.clone())));
+        assert!(is_invalid(StorageLayout::from_meta_info(&single_file("a", 16, 0, 1), destination.clone())));
+        assert!(is_invalid(StorageLayout::from_meta_info(&meta_info("dir", &[(&["a"], 10), (&["b"], 10)], 16, 1), destination)));
+    }
+}
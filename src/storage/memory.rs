@@ -0,0 +1,56 @@
+use std::sync::RwLock;
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::storage::backend::Storage;
+use crate::storage::layout::StorageLayout;
+
+//Keeps the whole torrent in memory, for tests and small torrents
+pub struct MemoryStorage {
+    pub layout: StorageLayout,
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(layout: StorageLayout) -> Self {
+        let data = vec![0; layout.length as usize];
+
+        Self {
+            layout,
+            data: RwLock::new(data),
+        }
+    }
+
+    //Files are only used for their offsets, the torrent byte space is stored as is
+    fn range(&self, piece_index: u32, begin: u32, length: u32) -> SyncResult<(usize, usize)> {
+        self.layout.map_block(piece_index, begin, length)?;
+        let offset = (self.layout.piece_offset(piece_index) + begin as u64) as usize;
+
+        Ok((offset, offset + length as usize))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+
+    fn read_block(&self, piece_index: u32, begin: u32, length: u32) -> SyncResult<Vec<u8>> {
+        let (start, end) = self.range(piece_index, begin, length)?;
+        let data = self.data.read().map_err(|_| Error::Poisoned("Memory storage"))?;
+
+        Ok(data[start..end].to_vec())
+    }
+
+    fn write_block(&self, piece_index: u32, begin: u32, data: &[u8]) -> SyncResult<()> {
+        let (start, end) = self.range(piece_index, begin, data.len() as u32)?;
+        let mut stored = self.data.write().map_err(|_| Error::Poisoned("Memory storage"))?;
+
+        stored[start..end].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    fn flush(&self) -> SyncResult<()> {
+        Ok(())
+    }
+}
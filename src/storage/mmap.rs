@@ -0,0 +1,97 @@
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use memmap2::MmapMut;
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::storage::backend::Storage;
+use crate::storage::layout::StorageLayout;
+
+//Maps every file in memory, blocks are copied in and out without a system call each
+pub struct MmapStorage {
+    pub layout: StorageLayout,
+    //Empty files cannot be mapped, they never hold a byte of a block anyway
+    maps: Vec<Option<Mutex<MmapMut>>>,
+}
+
+impl MmapStorage {
+    pub fn create(layout: StorageLayout) -> SyncResult<Self> {
+        let mut maps = Vec::with_capacity(layout.files.len());
+
+        for file in layout.files.iter() {
+            let parent = file.path.parent().ok_or_else(|| Error::Storage("Missing parent directory".into()))?;
+            std::fs::create_dir_all(parent).map_err(Error::Disk)?;
+
+            let handle = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&file.path)
+                .map_err(Error::Disk)?;
+            if handle.metadata().map_err(Error::Disk)?.len() != file.length {
+                handle.set_len(file.length).map_err(Error::Disk)?;
+            }
+
+            if file.length == 0 {
+                maps.push(None);
+                continue;
+            }
+
+            //The file is ours for as long as the storage lives, nothing else is expected to resize it
+            let map = unsafe { MmapMut::map_mut(&handle).map_err(Error::Disk)? };
+            maps.push(Some(Mutex::new(map)));
+        }
+
+        Ok(Self {
+            layout,
+            maps,
+        })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+
+    fn read_block(&self, piece_index: u32, begin: u32, length: u32) -> SyncResult<Vec<u8>> {
+        let spans = self.layout.map_block(piece_index, begin, length)?;
+        let mut data = vec![0; length as usize];
+
+        let mut position = 0;
+        for span in spans {
+            let map = self.maps[span.file_index].as_ref().ok_or_else(|| Error::Storage("File is not mapped".into()))?;
+            let map = map.lock().map_err(|_| Error::Poisoned("Storage map"))?;
+            let end = position + span.length as usize;
+
+            data[position..end].copy_from_slice(&map[span.offset as usize..(span.offset + span.length) as usize]);
+            position = end;
+        }
+
+        Ok(data)
+    }
+
+    fn write_block(&self, piece_index: u32, begin: u32, data: &[u8]) -> SyncResult<()> {
+        let spans = self.layout.map_block(piece_index, begin, data.len() as u32)?;
+
+        let mut position = 0;
+        for span in spans {
+            let map = self.maps[span.file_index].as_ref().ok_or_else(|| Error::Storage("File is not mapped".into()))?;
+            let mut map = map.lock().map_err(|_| Error::Poisoned("Storage map"))?;
+            let end = position + span.length as usize;
+
+            map[span.offset as usize..(span.offset + span.length) as usize].copy_from_slice(&data[position..end]);
+            position = end;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> SyncResult<()> {
+        for map in self.maps.iter().flatten() {
+            map.lock().map_err(|_| Error::Poisoned("Storage map"))?.flush().map_err(Error::Disk)?;
+        }
+
+        Ok(())
+    }
+}
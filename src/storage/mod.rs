@@ -0,0 +1,8 @@
+pub mod layout;
+pub mod backend;
+pub mod cache;
+pub mod disk;
+pub mod memory;
+pub mod mmap;
+pub mod resume;
+pub mod recheck;
\ No newline at end of file
@@ -0,0 +1,113 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use async_channel::Sender;
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::storage::backend::Storage;
+use crate::storage::disk::FileStorage;
+use crate::storage::layout::StorageLayout;
+use crate::types::bencode::MetaInfoFile;
+use crate::types::bitfield::BitField;
+use crate::types::piece::PieceWork;
+use crate::utils::data::manipulator;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecheckProgress {
+    pub index: u32,
+    pub valid: bool,
+    pub checked: u32,
+    pub total: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecheckReport {
+    pub have: BitField,
+    //Pieces whose data could be read but does not match its hash
+    pub mismatched: Vec<u32>,
+}
+
+//Hashes the data already in a storage, pieces are spread over several threads
+pub struct Rechecker {
+    pub storage: Arc<dyn Storage>,
+    pub pieces: Vec<PieceWork>,
+    pub threads: usize,
+}
+
+impl Rechecker {
+    pub fn new(storage: Arc<dyn Storage>, pieces: Vec<PieceWork>) -> Self {
+        let threads = std::thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1);
+
+        Self {
+            storage,
+            pieces,
+            threads,
+        }
+    }
+
+    //Checks the files under destination, they are only opened for reading so a recheck never changes them
+    pub fn from_meta_info(meta_info: &MetaInfoFile, destination: PathBuf) -> SyncResult<Self> {
+        let layout = StorageLayout::from_meta_info(meta_info, destination)?;
+        let pieces = manipulator::split_piece_bytes(meta_info)?;
+        let works = manipulator::piece_works(&pieces, layout.length, layout.piece_length);
+
+        Ok(Rechecker::new(Arc::new(FileStorage::open(layout)), works))
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub async fn run(self, progress: Option<Sender<RecheckProgress>>) -> SyncResult<RecheckReport> {
+        tokio::task::spawn_blocking(move || self.run_blocking(progress.as_ref())).await?
+    }
+
+    //Every thread takes the next unchecked piece until none is left
+    pub fn run_blocking(&self, progress: Option<&Sender<RecheckProgress>>) -> SyncResult<RecheckReport> {
+        let total = self.pieces.len() as u32;
+        let next = AtomicU32::new(0);
+        let checked = AtomicU32::new(0);
+        let report = Mutex::new(RecheckReport {
+            have: BitField::empty(total),
+            mismatched: Vec::new(),
+        });
+
+        std::thread::scope(|scope| {
+            for _ in 0..self.threads {
+                scope.spawn(|| {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let piece = match self.pieces.get(index as usize) {
+                            Some(piece) => piece,
+                            None => break,
+                        };
+
+                        //Missing or short files simply leave the piece out
+                        let verified = self.storage.verify_piece(piece);
+                        let valid = matches!(verified, Ok(true));
+
+                        if let Ok(mut report) = report.lock() {
+                            match verified {
+                                Ok(true) => report.have.set_piece(index),
+                                Ok(false) => report.mismatched.push(index),
+                                Err(_) => {},
+                            }
+                        }
+
+                        let checked = checked.fetch_add(1, Ordering::Relaxed) + 1;
+                        //Waits for room in a bounded channel rather than dropping updates, a closed one only means nobody listens
+                        if let Some(progress) = progress {
+                            let _ = progress.send_blocking(RecheckProgress { index, valid, checked, total });
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut report = report.into_inner().map_err(|_| Error::Poisoned("Recheck report"))?;
+        report.mismatched.sort_unstable();
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use serde_bytes::ByteBuf;
+use serde_derive::{Serialize, Deserialize};
+use crate::error::Error;
+use crate::shared::SyncResult;
+use crate::storage::layout::StorageLayout;
+use crate::types::bitfield::BitField;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeFile {
+    pub length: u64,
+    //Seconds since the epoch, and the nanoseconds within that second
+    pub mtime: u64,
+    #[serde(rename = "mtime-nanos", default)]
+    pub mtime_nanos: u32,
+}
+
+//Fast-resume state of a torrent, lets a restart skip the pieces already on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info-hash")]
+    pub info_hash: String,
+    //Completed pieces, in the same format as the bitfield message
+    pub pieces: ByteBuf,
+    pub files: Vec<ResumeFile>,
+
+    //Tracker ids handed out by the trackers, keyed by announce url
+    #[serde(rename = "tracker-ids", default)]
+    pub tracker_ids: BTreeMap<String, String>,
+}
+
+impl ResumeData {
+    //Next to the downloaded files, unless a state directory is configured
+    pub fn path(destination: &Path, state_directory: Option<&Path>, info_hash: &str) -> PathBuf {
+        state_directory.unwrap_or(destination).join(format!("{}.resume", info_hash))
+    }
+
+    //Snapshots the files as they are now, every piece in have must already be written
+    pub fn capture(info_hash: &str, have: &BitField, layout: &StorageLayout, tracker_ids: BTreeMap<String, String>) -> SyncResult<Self> {
+        let mut files = Vec::with_capacity(layout.files.len());
+        for file in layout.files.iter() {
+            let modified = ResumeData::modified(&file.path)?;
+            files.push(ResumeFile {
+                length: std::fs::metadata(&file.path).map_err(Error::Disk)?.len(),
+                mtime: modified.as_secs(),
+                mtime_nanos: modified.subsec_nanos(),
+            });
+        }
+
+        Ok(Self {
+            info_hash: info_hash.to_string(),
+            pieces: ByteBuf::from(have.bits.clone()),
+            files,
+            tracker_ids,
+        })
+    }
+
+    pub fn load(path: &Path) -> SyncResult<Option<Self>> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(Error::Disk(error)),
+        };
+
+        Ok(Some(serde_bencode::from_bytes(&bytes)?))
+    }
+
+    //Written aside then renamed, a crash while saving leaves the previous file intact
+    pub fn save(&self, path: &Path) -> SyncResult<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(Error::Disk)?;
+        }
+
+        let temporary = path.with_extension("resume.tmp");
+        std::fs::write(&temporary, serde_bencode::to_bytes(self)?).map_err(Error::Disk)?;
+        std::fs::rename(&temporary, path).map_err(Error::Disk)?;
+
+        Ok(())
+    }
+
+    //Completed pieces that can be trusted as they are, and the completed pieces of changed files, to be hashed again
+    pub fn verified_pieces(&self, info_hash: &str, layout: &StorageLayout, piece_count: u32) -> (BitField, Vec<u32>) {
+        let mut have = BitField::empty(piece_count);
+        let mut recheck = Vec::new();
+
+        //Resume data of another torrent, or of another version of it, is ignored
+        if self.info_hash != info_hash || self.files.len() != layout.files.len() || self.pieces.len() != have.bits.len() {
+            return (have, recheck);
+        }
+
+        have.bits.copy_from_slice(&self.pieces);
+
+        for (file, saved) in layout.files.iter().zip(self.files.iter()) {
+            //Any write after the last save changes the size or the modification time, ours included since they may not have completed
+            let unchanged = std::fs::metadata(&file.path).ok()
+                .filter(|metadata| metadata.len() == saved.length)
+                .and_then(|_| ResumeData::modified(&file.path).ok())
+                .is_some_and(|mtime| mtime.as_secs() == saved.mtime && mtime.subsec_nanos() == saved.mtime_nanos);
+
+            if unchanged || file.length == 0 {
+                continue;
+            }
+
+            let first = file.offset / layout.piece_length as u64;
+            let last = (file.offset + file.length - 1) / layout.piece_length as u64;
+            for index in (first..=last).map(|index| index as u32) {
+                if have.has_piece(index) {
+                    have.clear_piece(index);
+                    recheck.push(index);
+                }
+            }
+        }
+
+        (have, recheck)
+    }
+
+    fn modified(path: &Path) -> SyncResult<Duration> {
+        let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).map_err(Error::Disk)?;
+
+        //Files dated before the epoch never match a save
+        Ok(modified.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::SystemTime;
+    use super::*;
+
+    const INFO_HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    //Three files over three pieces of 16 bytes, a and b share the first piece, b and c the second one, c has the last one to itself
+    fn layout() -> (PathBuf, StorageLayout) {
+        let directory = std::env::temp_dir().join(format!("resume-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let files = [("a", 10), ("b", 20), ("c", 5)].map(|(name, length)| {
+            let path = directory.join(name);
+            std::fs::write(&path, vec![0; length as usize]).unwrap();
+            (path, length)
+        });
+
+        (directory, StorageLayout::new(files.to_vec(), 16))
+    }
+
+    fn capture(layout: &StorageLayout) -> ResumeData {
+        ResumeData::capture(INFO_HASH, &BitField::full(3), layout, BTreeMap::new()).unwrap()
+    }
+
+    fn pieces(have: &BitField) -> Vec<u32> {
+        (0..3).filter(|index| have.has_piece(*index)).collect()
+    }
+
+    #[test]
+    fn unchanged_files_are_trusted() {
+        let (directory, layout) = layout();
+        let resume = capture(&layout);
+
+        let (have, recheck) = resume.verified_pieces(INFO_HASH, &layout, 3);
+        assert_eq!(pieces(&have), vec![0, 1, 2]);
+        assert!(recheck.is_empty());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn only_pieces_of_changed_files_are_rechecked() {
+        let (directory, layout) = layout();
+        let resume = capture(&layout);
+
+        //Another size
+        std::fs::write(&layout.files[2].path, vec![0; 4]).unwrap();
+        let (have, recheck) = resume.verified_pieces(INFO_HASH, &layout, 3);
+        assert_eq!(pieces(&have), vec![0]);
+        assert_eq!(recheck, vec![1, 2]);
+
+        //The same size with another modification time
+        std::fs::write(&layout.files[2].path, vec![0; 5]).unwrap();
+        let resume = capture(&layout);
+        let modified = SystemTime::now() - Duration::from_secs(3600);
+        File::options().write(true).open(&layout.files[0].path).unwrap().set_modified(modified).unwrap();
+        let (have, recheck) = resume.verified_pieces(INFO_HASH, &layout, 3);
+        assert_eq!(pieces(&have), vec![1, 2]);
+        assert_eq!(recheck, vec![0]);
+
+        //A missing file, pieces that were not completed are not rechecked
+        std::fs::remove_file(&layout.files[1].path).unwrap();
+        let mut partial = resume.clone();
+        partial.pieces = ByteBuf::from(vec![0b1000_0000]);
+        let (have, recheck) = partial.verified_pieces(INFO_HASH, &layout, 3);
+        assert!(pieces(&have).is_empty());
+        assert_eq!(recheck, vec![0]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn resume_data_of_another_torrent_is_ignored() {
+        let (directory, layout) = layout();
+        let resume = capture(&layout);
+
+        let (have, recheck) = resume.verified_pieces("ffffffffffffffffffffffffffffffffffffffff", &layout, 3);
+        assert!(pieces(&have).is_empty());
+        assert!(recheck.is_empty());
+
+        //Another number of files or of pieces is another version of the torrent
+        let mut fewer_files = resume.clone();
+        fewer_files.files.pop();
+        assert!(pieces(&fewer_files.verified_pieces(INFO_HASH, &layout, 3).0).is_empty());
+
+        let mut more_pieces = resume.clone();
+        more_pieces.pieces = ByteBuf::from(vec![0xff, 0xff]);
+        assert!(pieces(&more_pieces.verified_pieces(INFO_HASH, &layout, 3).0).is_empty());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let (directory, layout) = layout();
+        let path = ResumeData::path(&directory, None, INFO_HASH);
+        assert_eq!(path, directory.join(format!("{}.resume", INFO_HASH)));
+        assert!(ResumeData::load(&path).unwrap().is_none());
+
+        let mut resume = capture(&layout);
+        resume.tracker_ids.insert("http://tracker.example/announce".into(), "id".into());
+        resume.save(&path).unwrap();
+
+        let loaded = ResumeData::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.info_hash, INFO_HASH);
+        assert_eq!(loaded.pieces, resume.pieces);
+        assert_eq!(loaded.tracker_ids, resume.tracker_ids);
+        assert_eq!(pieces(&loaded.verified_pieces(INFO_HASH, &layout, 3).0), vec![0, 1, 2]);
+
+        std::fs::write(&path, b"garbage").unwrap();
+        assert!(ResumeData::load(&path).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
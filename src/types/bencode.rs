@@ -1,6 +1,8 @@
 use std::path::PathBuf;
 use serde_bytes::ByteBuf;
 use serde_derive::{Serialize, Deserialize};
+use crate::error::Error;
+use crate::serializer::scanner;
 use crate::shared::SyncResult;
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -18,6 +20,10 @@ pub struct MetaInfoFile {
     pub created_by: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub encoding: Option<String>,
+
+    //Exact bencoded bytes of the info dictionary, including keys not modeled above
+    #[serde(skip)]
+    pub info_bytes: ByteBuf,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -31,7 +37,7 @@ pub struct MetaInfoDictionary {
 
     //Single file mode
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub length: Option<u32>,
+    pub length: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub md5sum: Option<String>,
 
@@ -43,7 +49,7 @@ pub struct MetaInfoDictionary {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct MetaInfoFileEntry {
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub length: Option<u32>,
+    pub length: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -68,16 +74,55 @@ pub struct TrackerResponse {
     pub incomplete: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub peers: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peers6: Option<ByteBuf>,
 }
 
 impl MetaInfoFile {
     pub async fn from_file(meta_info: PathBuf) -> SyncResult<Self> {
-        let raw_file = tokio::fs::read(meta_info).await?;
-        let meta_info = serde_bencode::from_bytes(&raw_file)?;
+        let raw_file = tokio::fs::read(meta_info).await.map_err(Error::Disk)?;
+
+        Self::from_bytes(&raw_file)
+    }
+
+    pub fn from_bytes(raw_file: &[u8]) -> SyncResult<Self> {
+        let mut meta_info: MetaInfoFile = serde_bencode::from_bytes(raw_file)?;
+        meta_info.info_bytes = ByteBuf::from(scanner::find_dictionary_value(raw_file, b"info")?);
 
         Ok(meta_info)
     }
 
+    //Builds the metainfo of a magnet link once its info dictionary was fetched from peers
+    pub fn from_info_bytes(info_bytes: &[u8], trackers: &[String]) -> SyncResult<Self> {
+        let info: MetaInfoDictionary = serde_bencode::from_bytes(info_bytes)?;
+
+        //Every tracker of a magnet link is its own tier
+        let announce_list = match trackers.is_empty() {
+            true => None,
+            false => Some(trackers.iter().map(|tracker| vec![tracker.clone()]).collect()),
+        };
+
+        Ok(Self {
+            info,
+            announce: trackers.first().cloned().unwrap_or_default(),
+            announce_list,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            info_bytes: ByteBuf::from(info_bytes),
+        })
+    }
+
+    //Re-encoding drops unknown keys, so it is only a fallback for metainfo built in memory
+    pub fn encoded_info(&self) -> SyncResult<Vec<u8>> {
+        if self.info_bytes.is_empty() {
+            return Ok(serde_bencode::to_bytes(&self.info)?);
+        }
+
+        Ok(self.info_bytes.to_vec())
+    }
+
     pub fn is_single_file_mode(&self) -> bool {
         self.info.length.is_some() && self.info.files.is_none()
     }
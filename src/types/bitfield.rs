@@ -1,3 +1,4 @@
+#[derive(Debug, Clone)]
 pub struct BitField {
     pub bits: Vec<u8>,
 }
@@ -7,6 +8,28 @@ impl BitField {
         BitField { bits }
     }
 
+    pub fn empty(pieces: u32) -> BitField {
+        BitField { bits: vec![0; pieces.div_ceil(8) as usize] }
+    }
+
+    //Spare bits at the end stay cleared, peers may drop us otherwise
+    pub fn full(pieces: u32) -> BitField {
+        let mut bitfield = BitField::empty(pieces);
+        for index in 0..pieces {
+            bitfield.set_piece(index);
+        }
+
+        bitfield
+    }
+
+    pub fn count_pieces(&self) -> u32 {
+        self.bits.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    pub fn is_complete(&self, pieces: u32) -> bool {
+        (0..pieces).all(|index| self.has_piece(index))
+    }
+
     pub fn has_piece(&self, index: u32) -> bool {
         let byte = index / 8;
         let offset = index % 8;
@@ -28,4 +51,15 @@ impl BitField {
 
         self.bits[byte as usize] |= 1 << (7 - offset);
     }
+
+    pub fn clear_piece(&mut self, index: u32) {
+        let byte = index / 8;
+        let offset = index % 8;
+
+        if byte >= self.bits.len() as u32 {
+            return;
+        }
+
+        self.bits[byte as usize] &= !(1 << (7 - offset));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use serde_bytes::ByteBuf;
+use serde_derive::{Serialize, Deserialize};
+
+//Extended message id reserved for the extended handshake itself
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+pub const METADATA_EXTENSION: &str = "ut_metadata";
+//Metadata is exchanged in blocks of 16 KiB, only the last one may be shorter
+pub const METADATA_BLOCK_SIZE: u64 = 16384;
+//Requests we accept to have queued by a peer, they are answered as they arrive
+pub const REQUEST_QUEUE_SIZE: u32 = 250;
+pub const CLIENT_VERSION: &str = concat!("bit-torrent-rs ", env!("CARGO_PKG_VERSION"));
+
+pub const METADATA_REQUEST: u8 = 0;
+pub const METADATA_DATA: u8 = 1;
+pub const METADATA_REJECT: u8 = 2;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    //Extension names mapped to the message id the sender wants to receive them with, 0 disables one
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+
+    //Client name and version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    //Listen port of the sender, useful when it connected to us from another port
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    //Number of outstanding requests the sender accepts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    //Our address as seen by the sender, 4 or 16 bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataMessage {
+    pub msg_type: u8,
+    pub piece: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    pub fn new(listen_port: u16, peer_ip: IpAddr) -> Self {
+        let yourip = match peer_ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+
+        Self {
+            m: BTreeMap::new(),
+            v: Some(CLIENT_VERSION.to_string()),
+            p: Some(listen_port),
+            reqq: Some(REQUEST_QUEUE_SIZE),
+            yourip: Some(ByteBuf::from(yourip)),
+            metadata_size: None,
+        }
+    }
+
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        match self.m.get(name) {
+            Some(id) if *id > 0 && *id <= u8::MAX as i64 => Some(*id as u8),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::net::SocketAddr;
+use data_encoding::BASE32;
+use url::Url;
+use crate::error::Error;
+use crate::shared::{SizedBytes, SyncResult};
+use crate::types::peer::Peer;
+
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: SizedBytes,
+    //Display name, only a hint until the metadata is known
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    //Peers given with x.pe, only literal addresses are kept
+    pub peers: Vec<Peer>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> SyncResult<Self> {
+        let url = Url::parse(uri).map_err(|error| Error::MetaInfo(error.to_string()))?;
+        if url.scheme() != "magnet" {
+            return Err(Error::MetaInfo("Not a magnet link".into()));
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(MagnetLink::parse_info_hash(hash)?);
+                    }
+                },
+                "dn" => name = Some(value.into_owned()),
+                "tr" if !trackers.contains(&value.to_string()) => trackers.push(value.into_owned()),
+                //Only ip:port addresses are supported, hostnames are skipped
+                "x.pe" => if let Ok(address) = value.parse::<SocketAddr>() {
+                    peers.push(Peer::new(address.ip(), address.port()));
+                },
+                _ => {},
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or_else(|| Error::MetaInfo("Magnet link has no urn:btih exact topic".into()))?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+
+    //The hash is either 40 hex characters or 32 base32 characters
+    fn parse_info_hash(hash: &str) -> SyncResult<SizedBytes> {
+        let bytes = match hash.len() {
+            40 => hex::decode(hash).ok(),
+            32 => BASE32.decode(hash.to_ascii_uppercase().as_bytes()).ok(),
+            _ => None,
+        };
+        let bytes = bytes.ok_or_else(|| Error::MetaInfo("Info hash is neither hex nor base32 encoded".into()))?;
+
+        bytes.try_into().map_err(|_| Error::MetaInfo("Info hash is not 20 bytes long".into()))
+    }
+
+    pub fn info_hash_hex(&self) -> String {
+        hex::encode(self.info_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use super::*;
+
+    const INFO_HASH: SizedBytes = [0xab, 0x01, 0x23, 0x45, 0x67, 0x89, 0xcd, 0xef, 0x10, 0x32, 0x54, 0x76, 0x98, 0xba, 0xdc, 0xfe, 0x00, 0xff, 0x7f, 0x80];
+
+    #[test]
+    fn parses_hex_and_base32_info_hashes() {
+        let hex = hex::encode(INFO_HASH);
+        let base32 = BASE32.encode(&INFO_HASH);
+
+        for hash in [hex.clone(), hex.to_uppercase(), base32.clone(), base32.to_lowercase()] {
+            let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", hash)).unwrap();
+            assert_eq!(magnet.info_hash, INFO_HASH, "{}", hash);
+            assert_eq!(magnet.info_hash_hex(), hex);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_info_hashes() {
+        let invalid = [
+            "magnet:?dn=name",
+            "magnet:?xt=urn:sha1:0123456789abcdef0123456789abcdef01234567",
+            "magnet:?xt=urn:btih:0123456789abcdef",
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef0123456z",
+            "magnet:?xt=urn:btih:0123456789ABCDEF0123456789ABCDEF",
+            "http://example.com/?xt=urn:btih:0123456789abcdef0123456789abcdef01234567",
+            "not a link",
+        ];
+
+        for uri in invalid {
+            assert!(matches!(MagnetLink::parse(uri), Err(Error::MetaInfo(_))), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn keeps_each_tracker_once() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=Some%20name&tr=udp%3A%2F%2Ftracker.example%3A6969&tr=http%3A%2F%2Fother.example%2Fannounce&tr=udp%3A%2F%2Ftracker.example%3A6969",
+            hex::encode(INFO_HASH),
+        );
+        let magnet = MagnetLink::parse(&uri).unwrap();
+
+        assert_eq!(magnet.name.as_deref(), Some("Some name"));
+        assert_eq!(magnet.trackers, vec!["udp://tracker.example:6969", "http://other.example/announce"]);
+        assert!(magnet.peers.is_empty());
+    }
+
+    #[test]
+    fn parses_peer_addresses() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&x.pe=10.0.0.1%3A6881&x.pe=%5B2001%3Adb8%3A%3A1%5D%3A51413&x.pe=peer.example%3A6881&x.pe=10.0.0.2",
+            hex::encode(INFO_HASH),
+        );
+        let magnet = MagnetLink::parse(&uri).unwrap();
+
+        //Hostnames and addresses without a port are skipped
+        assert_eq!(magnet.peers, vec![
+            Peer::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 6881),
+            Peer::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)), 51413),
+        ]);
+        assert!(magnet.name.is_none());
+        assert!(magnet.trackers.is_empty());
+    }
+}
@@ -1,4 +1,15 @@
-use crate::shared::{PEER_ID, SizedBytes, SyncResult};
+use crate::shared::SizedBytes;
+
+//Capability bits of the reserved handshake bytes
+pub const EXTENSION_PROTOCOL_FLAG: ReservedFlag = ReservedFlag { byte: 5, mask: 0x10 };
+pub const DHT_FLAG: ReservedFlag = ReservedFlag { byte: 7, mask: 0x01 };
+pub const FAST_FLAG: ReservedFlag = ReservedFlag { byte: 7, mask: 0x04 };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedFlag {
+    pub byte: usize,
+    pub mask: u8,
+}
 
 #[derive(Clone, PartialEq)]
 pub enum MessageCode {
@@ -11,6 +22,14 @@ pub enum MessageCode {
     MessageRequest = 6,
     MessagePiece = 7,
     MessageCancel = 8,
+    //Fast extension messages (BEP 6)
+    MessageSuggest = 13,
+    MessageHaveAll = 14,
+    MessageHaveNone = 15,
+    MessageReject = 16,
+    MessageAllowedFast = 17,
+    //Extension protocol message, the first payload byte is the extension id (BEP 10)
+    MessageExtended = 20,
     //Keep-alive message
     MessageKeepAlive = 254,
     //Rust needs a way to specify the last value in an enum
@@ -24,6 +43,8 @@ pub struct Message {
 
 pub struct Handshake {
     pub pstr: String,
+    //Capability flags, each bit advertises a protocol extension
+    pub reserved: [u8; 8],
     pub info_hash: String,
     pub peer_id: SizedBytes,
 }
@@ -38,14 +59,32 @@ impl Message {
 }
 
 impl Handshake {
-    pub fn new(info_hash: String) -> SyncResult<Handshake> {
-        let peer_id = PEER_ID.get().ok_or("Failed to get peer id, is it set ?")?.clone();
-
-        Ok(Handshake {
+    pub fn new(info_hash: String, peer_id: SizedBytes) -> Handshake {
+        Handshake {
             pstr: "BitTorrent protocol".to_string(),
+            reserved: ReservedFlag::set(ReservedFlag::set([0; 8], EXTENSION_PROTOCOL_FLAG), FAST_FLAG),
             info_hash,
             peer_id,
-        })
+        }
+    }
+
+    pub fn set_flag(&mut self, flag: ReservedFlag) {
+        self.reserved = ReservedFlag::set(self.reserved, flag);
+    }
+
+    pub fn has_flag(&self, flag: ReservedFlag) -> bool {
+        flag.is_set(&self.reserved)
+    }
+}
+
+impl ReservedFlag {
+    pub fn set(mut reserved: [u8; 8], flag: ReservedFlag) -> [u8; 8] {
+        reserved[flag.byte] |= flag.mask;
+        reserved
+    }
+
+    pub fn is_set(&self, reserved: &[u8; 8]) -> bool {
+        reserved[self.byte] & self.mask != 0
     }
 }
 
@@ -61,14 +100,20 @@ impl From<u8> for MessageCode {
             6 => MessageCode::MessageRequest,
             7 => MessageCode::MessagePiece,
             8 => MessageCode::MessageCancel,
+            13 => MessageCode::MessageSuggest,
+            14 => MessageCode::MessageHaveAll,
+            15 => MessageCode::MessageHaveNone,
+            16 => MessageCode::MessageReject,
+            17 => MessageCode::MessageAllowedFast,
+            20 => MessageCode::MessageExtended,
             254 => MessageCode::MessageKeepAlive,
             _ => MessageCode::MessageUnknown,
         }
     }
 }
 
-impl Into<u8> for MessageCode {
-    fn into(self) -> u8 {
-        self as u8
+impl From<MessageCode> for u8 {
+    fn from(code: MessageCode) -> Self {
+        code as u8
     }
 }
\ No newline at end of file
@@ -3,3 +3,7 @@ pub mod peer;
 pub mod message;
 pub mod piece;
 pub mod bencode;
+pub mod stats;
+
+pub mod magnet;
+pub mod extension;
\ No newline at end of file
@@ -1,7 +1,7 @@
 use std::net::IpAddr;
 use serde_derive::{Serialize, Deserialize};
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Peer {
     pub ip: IpAddr,
     pub port: u16,
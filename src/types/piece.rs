@@ -21,6 +21,15 @@ pub struct PieceProgress {
     pub downloaded: u32,
     pub requested: u32,
     pub backlog: u32,
+    //Size of the blocks requested, only the last one may be shorter
+    pub block_size: u32,
+
+    //Blocks requested and not received yet, as (begin, length)
+    pub pending: Vec<(u32, u32)>,
+    //Blocks the peer rejected or dropped, requested again before new ones
+    pub retry: Vec<(u32, u32)>,
+    //Begins of the blocks we have, from this peer or from another one in endgame
+    pub received: Vec<u32>,
 }
 
 impl PieceWork {
@@ -43,13 +52,61 @@ impl PieceResult {
 }
 
 impl PieceProgress {
-    pub fn new(index: u32) -> PieceProgress {
+    pub fn new(index: u32, length: u32, block_size: u32) -> PieceProgress {
         PieceProgress {
             index,
-            data: Vec::new(),
+            data: vec![0; length as usize],
             downloaded: 0,
             requested: 0,
             backlog: 0,
+            block_size,
+
+            pending: Vec::new(),
+            retry: Vec::new(),
+            received: Vec::new(),
+        }
+    }
+
+    //Blocks another peer delivered in endgame are skipped
+    pub fn next_block(&mut self) -> Option<(u32, u32)> {
+        while let Some(block) = self.retry.pop() {
+            if !self.received.contains(&block.0) {
+                return Some(block);
+            }
         }
+
+        while self.requested < self.data.len() as u32 {
+            let block = (self.requested, self.block_length(self.requested));
+            self.requested += block.1;
+
+            if !self.received.contains(&block.0) {
+                return Some(block);
+            }
+        }
+
+        None
+    }
+
+    pub fn block_length(&self, begin: u32) -> u32 {
+        self.block_size.min((self.data.len() as u32).saturating_sub(begin))
+    }
+
+    //Puts a pending block back in the queue, returns false when it was not pending
+    pub fn requeue_block(&mut self, begin: u32) -> bool {
+        let position = match self.pending.iter().position(|(pending, _)| *pending == begin) {
+            Some(position) => position,
+            None => return false,
+        };
+
+        let block = self.pending.remove(position);
+        self.retry.push(block);
+        self.backlog -= 1;
+
+        true
+    }
+
+    pub fn requeue_all(&mut self) {
+        self.retry.append(&mut self.pending);
+        self.backlog = 0;
     }
 }
\ No newline at end of file
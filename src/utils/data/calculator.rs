@@ -1,6 +1,7 @@
-pub fn calculate_bounds_for_piece(length: u32, piece_length: u32, piece_index: u32) -> (u32, u32) {
-    let begin = piece_index * piece_length;
-    let mut end = begin + piece_length;
+//Piece bounds are absolute offsets in the torrent byte space, which can exceed 4 GiB
+pub fn calculate_bounds_for_piece(length: u64, piece_length: u32, piece_index: u32) -> (u64, u64) {
+    let begin = piece_index as u64 * piece_length as u64;
+    let mut end = begin + piece_length as u64;
 
     if end > length {
         end = length;
@@ -9,8 +10,9 @@ pub fn calculate_bounds_for_piece(length: u32, piece_length: u32, piece_index: u
     (begin, end)
 }
 
-pub fn calculate_piece_size(length: u32, piece_length: u32, piece_index: u32) -> u32 {
+//A single piece always fits in 32 bits, only its position in the torrent needs 64
+pub fn calculate_piece_size(length: u64, piece_length: u32, piece_index: u32) -> u32 {
     let (begin, end) = calculate_bounds_for_piece(length, piece_length, piece_index);
 
-    end - begin
+    (end - begin) as u32
 }
\ No newline at end of file
@@ -1,36 +1,96 @@
+use std::net::Ipv4Addr;
 use sha1::{Digest, Sha1};
+use crate::error::Error;
 use crate::shared::{SizedBytes, SyncResult};
 use crate::types::bencode::MetaInfoFile;
+use crate::types::piece::PieceWork;
+use crate::utils::data::calculator;
 
 pub fn hash_meta_info(to_hash: &MetaInfoFile) -> SyncResult<String> {
-    let encoded = serde_bencode::to_bytes(&to_hash.info)?;
+    let encoded = to_hash.encoded_info()?;
     let digest = Sha1::digest(&encoded);
 
     let mut info_hash = [0; 20];
     info_hash.copy_from_slice(&digest);
 
-    let hex_encoded = hex::encode(&info_hash);
+    let hex_encoded = hex::encode(info_hash);
 
     Ok(hex_encoded)
 }
 
+pub fn hash_piece(data: &[u8]) -> SizedBytes {
+    let digest = Sha1::digest(data);
+
+    let mut piece_hash = [0; 20];
+    piece_hash.copy_from_slice(&digest);
+
+    piece_hash
+}
+
+//Big-endian integers of the wire formats, None when the bytes end before them
+pub fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes.get(offset..offset.checked_add(4)?)?.try_into().ok().map(u32::from_be_bytes)
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    bytes.get(offset..offset.checked_add(8)?)?.try_into().ok().map(u64::from_be_bytes)
+}
+
 pub fn split_piece_bytes(to_split: &MetaInfoFile) -> SyncResult<Vec<SizedBytes>> {
     let mut pieces = Vec::new();
 
     let raw_pieces = to_split.info.pieces.as_ref();
-    println!("[split_piece_bytes] Raw pieces size: {}", raw_pieces.len());
-    let piece_length = to_split.info.piece_length;
-    println!("[split_piece_bytes] Piece length: {}", piece_length);
 
-    let mut index = 0;
-    while index < raw_pieces.len() {
+    if !raw_pieces.len().is_multiple_of(20) {
+        return Err(Error::MetaInfo("Pieces length is not a multiple of 20".into()));
+    }
+
+    //Each piece hash is a 20 bytes SHA-1 digest, laid out back to back
+    for raw_piece in raw_pieces.chunks_exact(20) {
         let mut piece = [0; 20];
 
-        piece.copy_from_slice(&raw_pieces[index..index + 20]);
+        piece.copy_from_slice(raw_piece);
         pieces.push(piece);
-
-        index += piece_length as usize;
     }
 
     Ok(pieces)
-}
\ No newline at end of file
+}
+
+//Pieces a peer at this address may request while choked, computed as described in BEP 6
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &SizedBytes, piece_count: u32, count: usize) -> Vec<u32> {
+    let mut allowed = Vec::new();
+    if piece_count == 0 {
+        return allowed;
+    }
+
+    let count = count.min(piece_count as usize);
+
+    //Only the /24 network counts, peers behind the same NAT get the same set
+    let mut hash = Vec::with_capacity(24);
+    hash.extend_from_slice(&(u32::from(ip) & 0xFFFFFF00).to_be_bytes());
+    hash.extend_from_slice(info_hash);
+
+    while allowed.len() < count {
+        hash = Sha1::digest(&hash).to_vec();
+
+        for chunk in hash.chunks_exact(4) {
+            if allowed.len() >= count {
+                break;
+            }
+
+            let index = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) % piece_count;
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+
+    allowed
+}
+
+pub fn piece_works(pieces: &[SizedBytes], length: u64, piece_length: u32) -> Vec<PieceWork> {
+    pieces.iter().enumerate().map(|(index, hash)| {
+        let length = calculator::calculate_piece_size(length, piece_length, index as u32);
+        PieceWork::new(index as u32, *hash, length)
+    }).collect()
+}